@@ -235,7 +235,8 @@ ipcMain.handle('start-site', async (event, siteId) => {
     const args = [
       '--root', site.root,
       '--port', site.port.toString(),
-      '--host', '0.0.0.0'
+      '--host', '0.0.0.0',
+      '--output', 'json'
     ];
 
     if (site.https) {
@@ -274,35 +275,34 @@ ipcMain.handle('start-site', async (event, siteId) => {
       global.siteUrls?.delete(siteId);
     });
 
+    // server-cli writes one JSON event per line in --output json mode
+    let stdoutBuffer = '';
     siteProcess.stdout.on('data', (data) => {
-      const output = data.toString();
-      console.log(`Site ${siteId} stdout:`, output);
-      
-      // Parse URLs from server output
-      const urls = {};
-      
-      // Match Local URL
-      const localMatch = output.match(/📱 Local: (http:\/\/localhost:\d+)/);
-      if (localMatch) {
-        urls.local = localMatch[1];
-      }
-      
-      // Match Network URL
-      const networkMatch = output.match(/📱 Network: (http:\/\/[\d.]+:\d+)/);
-      if (networkMatch) {
-        urls.network = networkMatch[1];
-      }
-      
-      // Match Internet URL
-      const internetMatch = output.match(/🌍 Internet: (http:\/\/[\d.]+:\d+)/);
-      if (internetMatch) {
-        urls.internet = internetMatch[1];
-      }
-      
-      // Store URLs if we found any
-      if (Object.keys(urls).length > 0) {
-        global.siteUrls.set(siteId, urls);
-        console.log(`Site ${siteId} URLs:`, urls);
+      stdoutBuffer += data.toString();
+      const lines = stdoutBuffer.split('\n');
+      stdoutBuffer = lines.pop();
+
+      for (const line of lines) {
+        if (!line.trim()) continue;
+
+        let event;
+        try {
+          event = JSON.parse(line);
+        } catch (error) {
+          console.log(`Site ${siteId} stdout:`, line);
+          continue;
+        }
+
+        if (event.event === 'site_started') {
+          const urls = {};
+          if (event.urls.local) urls.local = event.urls.local;
+          if (event.urls.network) urls.network = event.urls.network;
+          if (event.urls.internet) urls.internet = event.urls.internet;
+          global.siteUrls.set(siteId, urls);
+          console.log(`Site ${siteId} URLs:`, urls);
+        } else if (event.event === 'fatal' || event.event === 'proxy_error' || event.event === 'site_failed') {
+          console.error(`Site ${siteId} ${event.event}:`, event.error);
+        }
       }
     });
 
@@ -0,0 +1,176 @@
+//! `server-cli cert ...`: look at and manage the certificates HTTPS sites use.
+
+use clap::{Subcommand, ValueEnum};
+use std::{error::Error, path::PathBuf};
+
+#[derive(Subcommand)]
+pub enum CertCommand {
+    /// Show where a certificate is stored, the names it covers and when it expires
+    Show {
+        #[arg(default_value = "localhost")]
+        hostname: String,
+    },
+    /// Replace a certificate with a new one for the same names and key type (clients will see a new key)
+    Regenerate {
+        #[arg(default_value = "localhost")]
+        hostname: String,
+    },
+    /// Write a certificate to a file or stdout, e.g. to trust it in a browser
+    Export {
+        #[arg(default_value = "localhost")]
+        hostname: String,
+        /// Encoding of the exported files
+        #[arg(long, value_enum, default_value_t = CertFormat::Pem)]
+        format: CertFormat,
+        /// File to write the certificate to (stdout if not given, PEM only)
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+        /// Also export the private key, next to the certificate as `<name>-key.<ext>`
+        #[arg(long, requires = "out")]
+        key: bool,
+    },
+    /// The local certificate authority that issues the site certificates
+    #[command(subcommand)]
+    Ca(CaCommand),
+}
+
+#[derive(Subcommand)]
+pub enum CaCommand {
+    /// Show where the CA is stored and when it expires
+    Show {
+        /// The CA of sites with an RSA key_type instead
+        #[arg(long)]
+        rsa: bool,
+    },
+    /// Write the CA certificate to a file or stdout, to trust it on other devices
+    Export {
+        /// The CA of sites with an RSA key_type instead
+        #[arg(long)]
+        rsa: bool,
+        /// Encoding of the exported file (DER is what most phones expect)
+        #[arg(long, value_enum, default_value_t = CertFormat::Pem)]
+        format: CertFormat,
+        /// File to write the CA certificate to (stdout if not given, PEM only)
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CertFormat {
+    Pem,
+    Der,
+}
+
+#[cfg(feature = "ssl")]
+pub fn run(command: CertCommand) -> Result<(), Box<dyn Error>> {
+    use crate::server::ssl::{write_atomic, CaKind, CertStore};
+
+    let ca_kind = |rsa: bool| if rsa { CaKind::Rsa } else { CaKind::Ecdsa };
+
+    let store = CertStore::open_default()?;
+    match command {
+        CertCommand::Show { hostname } => {
+            let (_, info) = store
+                .load(&hostname)
+                .map_err(|e| format!("No usable certificate for {} ({}), it is created when an HTTPS site starts", hostname, e))?;
+            println!("Certificate: {}", info.cert_path.display());
+            println!("Key:         {}", info.key_path.display());
+            println!("Issuer:      {}", info.issuer);
+            println!("Names:       {}", info.names.join(", "));
+            if let Some(key_algorithm) = info.key_algorithm {
+                println!("Key type:    {}", key_algorithm);
+            }
+            println!("Valid from:  {}", info.not_before.date());
+            println!("Valid until: {} ({} days left)", info.not_after.date(), info.days_left());
+        }
+        CertCommand::Regenerate { hostname } => {
+            store.regenerate(&hostname)?;
+            println!("New certificate for {} saved to {}", hostname, store.cert_path(&hostname).display());
+        }
+        CertCommand::Export { hostname, format, out, key } => {
+            let (pem, _) = store
+                .load(&hostname)
+                .map_err(|e| format!("No usable certificate for {} ({}), it is created when an HTTPS site starts", hostname, e))?;
+            let Some(out) = out else {
+                return print_pem(&pem.cert, format);
+            };
+
+            write_atomic(&out, &encode(&pem.cert, format, false)?, false)?;
+            println!("Certificate written to {}", out.display());
+            if key {
+                let key_out = key_path_for(&out);
+                write_atomic(&key_out, &encode(&pem.key, format, true)?, true)?;
+                println!("Private key written to {}", key_out.display());
+            }
+        }
+        CertCommand::Ca(CaCommand::Show { rsa }) => {
+            let (_, info) = store.ca(ca_kind(rsa))?;
+            println!("Certificate: {}", info.cert_path.display());
+            println!("Subject:     {}", info.subject);
+            println!("Valid until: {} ({} days left)", info.not_after.date(), info.days_left());
+            println!();
+            println!("Trust it once on each device that opens the sites:");
+            println!("  macOS:   sudo security add-trusted-cert -d -r trustRoot -k /Library/Keychains/System.keychain {}", info.cert_path.display());
+            println!("  Linux:   copy it to /usr/local/share/ca-certificates/localhostify.crt and run sudo update-ca-certificates");
+            println!("  Windows: certutil -addstore -f ROOT {}", info.cert_path.display());
+            println!("  Phones:  export it with --format der and open the file on the device");
+        }
+        CertCommand::Ca(CaCommand::Export { rsa, format, out }) => {
+            let (cert, _) = store.ca(ca_kind(rsa))?;
+            let Some(out) = out else {
+                return print_pem(&cert, format);
+            };
+            write_atomic(&out, &encode(&cert, format, false)?, false)?;
+            println!("CA certificate written to {}", out.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ssl")]
+fn print_pem(pem: &str, format: CertFormat) -> Result<(), Box<dyn Error>> {
+    if format == CertFormat::Der {
+        return Err("DER export needs --out".into());
+    }
+    print!("{}", pem);
+    Ok(())
+}
+
+#[cfg(not(feature = "ssl"))]
+pub fn run(_command: CertCommand) -> Result<(), Box<dyn Error>> {
+    Err("SSL feature not enabled".into())
+}
+
+/// Convert a PEM certificate or key to the requested encoding.
+#[cfg(feature = "ssl")]
+fn encode(pem: &str, format: CertFormat, is_key: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    if format == CertFormat::Pem {
+        return Ok(pem.as_bytes().to_vec());
+    }
+
+    let mut reader = std::io::Cursor::new(pem.as_bytes());
+    let der = if is_key {
+        rustls_pemfile::private_key(&mut reader)?
+            .ok_or("no private key found")?
+            .secret_der()
+            .to_vec()
+    } else {
+        rustls_pemfile::certs(&mut reader)
+            .next()
+            .ok_or("no certificate found")??
+            .to_vec()
+    };
+    Ok(der)
+}
+
+/// `cert.pem` → `cert-key.pem`
+#[cfg(feature = "ssl")]
+fn key_path_for(cert_path: &std::path::Path) -> PathBuf {
+    let stem = cert_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("cert");
+    let name = match cert_path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}-key.{}", stem, ext),
+        None => format!("{}-key", stem),
+    };
+    cert_path.with_file_name(name)
+}
@@ -0,0 +1,1310 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::network::NetworkInfo;
+use crate::server::{
+    cache::CachePolicy,
+    compression::compression_layer,
+    cors::cors_layer,
+    headers::HeaderPolicy,
+    listener::ListenerProtocol,
+    redirect::redirect_to_https,
+    routes::Routes,
+    ssl::{CertFiles, CertOptions, KeyAlgorithm, SiteCert, TlsConfig, MAX_CERT_DAYS},
+    upstream::compile_upstreams,
+    ServerConfig,
+};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SiteConfig {
+    pub name: String,
+    pub root: PathBuf,
+    pub port: u16,
+    pub https: bool,
+    pub h2c: bool,
+    pub redirect_http_port: Option<u16>,
+    pub redirect_plaintext: bool,
+    pub proxy_to: Option<u16>,
+    pub hostnames: Vec<String>,
+    pub default_host: bool,
+    pub restart: RestartPolicy,
+    pub max_restarts: Option<u32>,
+    pub max_request_body: Option<u64>,
+    pub max_response_body: Option<u64>,
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub headers: HeadersConfig,
+    pub cors: Option<CorsConfig>,
+    pub fallback_file: Option<PathBuf>,
+    pub directory_listing: bool,
+    pub error_pages: BTreeMap<u16, PathBuf>,
+    pub compression: CompressionConfig,
+    pub cache: CacheConfig,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub cert_days: Option<u32>,
+    pub key_type: Option<KeyAlgorithm>,
+}
+
+/// A `[sites.upstreams.NAME]` entry: one or more backends that routes can
+/// proxy to by name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Base URL, e.g. `http://192.168.1.20:4000` or `https://api.local/v2`
+    pub url: Option<String>,
+    /// Several base URLs to balance requests over, instead of `url`
+    pub urls: Option<Vec<String>>,
+    /// How requests are spread over `urls` (round-robin if unset)
+    pub strategy: Option<BalanceStrategy>,
+    /// Probe each backend and take failing ones out of rotation
+    pub health_check: Option<HealthCheckConfig>,
+    /// Take a backend out of rotation after this many connection errors in
+    /// a row (3 if unset, 0 to never)
+    pub max_failures: Option<u32>,
+    /// Seconds a backend stays out of rotation after `max_failures` (30 if unset)
+    pub eject_secs: Option<u64>,
+}
+
+/// How an upstream with several backends picks one for each request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    /// The backend with the fewest requests in flight
+    LeastConnections,
+    /// The same backend for each client address
+    IpHash,
+}
+
+/// Active health probe of an upstream's backends.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Path requested on each backend; any 2xx or 3xx answer is healthy
+    pub path: String,
+    /// Seconds between probes (10 if unset)
+    pub interval: Option<u64>,
+    /// Seconds to wait for an answer (2 if unset)
+    pub timeout: Option<u64>,
+}
+
+/// Where a route proxies to: a local port or the name of an upstream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ProxyTarget {
+    Port(u16),
+    Upstream(String),
+}
+
+impl std::str::FromStr for ProxyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Empty proxy target".to_string());
+        }
+        Ok(s.parse().map(Self::Port).unwrap_or_else(|_| Self::Upstream(s.to_string())))
+    }
+}
+
+/// One `[[sites.routes]]` entry: which paths it matches and what serves
+/// them. See `server::routes` for how rules are matched.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Path prefix matched on whole segments, e.g. `/api`
+    pub path: Option<String>,
+    /// Glob matched against the whole path, e.g. `/assets/**/*.js`
+    pub glob: Option<String>,
+    /// Regular expression searched in the path
+    pub regex: Option<String>,
+    /// Serve files from this directory
+    pub root: Option<PathBuf>,
+    /// Proxy to a local port or to a named upstream
+    pub proxy: Option<ProxyTarget>,
+    /// Remove the matched `path` prefix before proxying
+    pub strip_prefix: Option<bool>,
+    /// Replace the matched `path` prefix, or the `regex` match, before
+    /// proxying; `$1` or `$name` expand regex captures
+    pub rewrite: Option<String>,
+    /// Redirect to this URL; `$1` or `$name` expand regex captures
+    pub redirect: Option<String>,
+    /// Redirect status code (302 if unset)
+    pub status: Option<u16>,
+}
+
+/// The `[sites.headers]` policy: response headers added to or removed from
+/// everything the site serves, files and proxied responses alike. Headers
+/// set here replace those of the same name from a backend.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// `Strict-Transport-Security`, e.g. `max-age=31536000`. HTTPS sites
+    /// only; browsers apply it to the host name on every port.
+    pub hsts: Option<String>,
+    /// `Content-Security-Policy`, e.g. `default-src 'self'`
+    pub content_security_policy: Option<String>,
+    /// `X-Frame-Options`, e.g. `DENY` or `SAMEORIGIN`
+    pub x_frame_options: Option<String>,
+    /// `Referrer-Policy`, e.g. `strict-origin-when-cross-origin`
+    pub referrer_policy: Option<String>,
+    /// `Permissions-Policy`, e.g. `camera=(), geolocation=()`
+    pub permissions_policy: Option<String>,
+    /// Any other headers to set, by name
+    pub custom: Option<BTreeMap<String, String>>,
+    /// Headers to take out of responses, e.g. `x-powered-by`
+    pub remove: Option<Vec<String>>,
+}
+
+/// The `[sites.cors]` settings. Sites without them let any origin call
+/// them (see `server::cors`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Leave CORS to the backend: no CORS headers are added or replaced and
+    /// preflight requests are served like any other request
+    pub passthrough: Option<bool>,
+    /// Origins allowed to call the site, e.g. `http://localhost:5173`, or
+    /// `*` for any (the default)
+    pub allow_origins: Option<Vec<String>>,
+    /// Methods allowed in cross-origin requests (any if unset)
+    pub allow_methods: Option<Vec<String>>,
+    /// Request headers allowed in cross-origin requests (any if unset)
+    pub allow_headers: Option<Vec<String>>,
+    /// Response headers scripts may read besides the basic ones
+    pub expose_headers: Option<Vec<String>>,
+    /// Allow cookies and `Authorization`, needs the origins listed in
+    /// `allow_origins`
+    pub allow_credentials: Option<bool>,
+    /// Seconds browsers may reuse a preflight response
+    pub max_age: Option<u64>,
+}
+
+/// The `[sites.compression]` settings. Responses are compressed unless
+/// `enabled = false` (see `server::compression`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Compress responses (true if unset)
+    pub enabled: Option<bool>,
+    /// Offer gzip (true if unset)
+    pub gzip: Option<bool>,
+    /// Offer brotli (true if unset)
+    pub br: Option<bool>,
+    /// Offer zstd (true if unset)
+    pub zstd: Option<bool>,
+    /// Serve `.br`, `.gz` or `.zst` files found next to static files to
+    /// clients that accept them (true if unset)
+    pub precompressed: Option<bool>,
+    /// Smallest response compressed on the fly, e.g. `1KB` (1KB if unset)
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub min_size: Option<u64>,
+    /// Content types compressed on the fly, e.g. `application/json` or
+    /// `text/*` (text, JSON, JavaScript, XML, SVG and wasm if unset)
+    pub mime_types: Option<Vec<String>>,
+}
+
+/// The `[sites.cache]` settings for static files (see `server::cache`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Dev mode: tell browsers not to store anything and leave out
+    /// validators, whatever the rules say
+    pub dev: Option<bool>,
+    /// Send an `ETag` with files and answer matching `If-None-Match`
+    /// requests with 304 (true if unset)
+    pub etag: Option<bool>,
+    /// `Cache-Control` by path, the first matching rule applies
+    pub rules: Option<Vec<CacheRuleConfig>>,
+}
+
+/// One `[[sites.cache.rules]]` entry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRuleConfig {
+    /// Glob matched against the whole request path, e.g. `/assets/**`
+    pub glob: Option<String>,
+    /// File extensions, e.g. `["html"]`
+    pub extensions: Option<Vec<String>>,
+    /// e.g. `public, max-age=31536000, immutable` or `no-cache`
+    pub cache_control: String,
+}
+
+/// Document served by single-page app sites for unknown pages
+pub const DEFAULT_FALLBACK_FILE: &str = "index.html";
+
+/// Parse an error page option: `CODE=FILE`, e.g. `404=404.html`.
+pub fn parse_error_page(s: &str) -> Result<(u16, PathBuf), String> {
+    let (status, file) = s.split_once('=').ok_or_else(|| format!("Error page should be CODE=FILE: {}", s))?;
+    let status = status.parse().map_err(|_| format!("Invalid error page status: {}", status))?;
+    Ok((status, PathBuf::from(file)))
+}
+
+/// Files such as the SPA fallback are looked up under the site root and
+/// must stay inside it.
+fn validate_site_file(file: &Path, field: &str) -> Result<(), String> {
+    let inside_root = file
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_) | std::path::Component::CurDir));
+    if file.as_os_str().is_empty() || !inside_root {
+        return Err(format!("{} must be a path inside the site root: {}", field, file.display()));
+    }
+    Ok(())
+}
+
+/// Parse a `--site` route option: `PATTERN>TARGET[>STATUS]`.
+///
+/// PATTERN is a path prefix (`/api`), a glob when it contains `*`, `?`, `[`
+/// or `{` (`/assets/*.js`), or a regex when it starts with `~` (`~^/v\d+/`).
+/// TARGET is `proxy=PORT`, `proxy=UPSTREAM`, `root=DIR` or `redirect=URL`.
+/// The optional third part is the redirect status for redirects, and for
+/// proxies either `strip` to remove the matched prefix or a rewrite such as
+/// `/v2`. Since `--site` options are separated by `:`, no part can contain
+/// one.
+pub fn parse_route(s: &str) -> Result<RouteConfig, String> {
+    let mut parts = s.split('>');
+    let pattern = parts.next().unwrap_or_default();
+    let target = parts.next().ok_or_else(|| format!("Route {} needs a target: PATTERN>TARGET", s))?;
+
+    let mut route = RouteConfig::default();
+    if let Some(regex) = pattern.strip_prefix('~') {
+        route.regex = Some(regex.to_string());
+    } else if pattern.contains(['*', '?', '[', '{']) {
+        route.glob = Some(pattern.to_string());
+    } else {
+        route.path = Some(pattern.to_string());
+    }
+
+    match target.split_once('=') {
+        Some(("proxy", target)) => route.proxy = Some(target.parse()?),
+        Some(("root", root)) => route.root = Some(PathBuf::from(root)),
+        Some(("redirect", to)) => route.redirect = Some(to.to_string()),
+        _ => return Err(format!("Unknown route target {} (expected proxy=PORT, root=DIR or redirect=URL)", target)),
+    }
+
+    match parts.next() {
+        Some(status) if route.redirect.is_some() => {
+            route.status = Some(status.parse().map_err(|_| format!("Invalid redirect status in route {}", s))?);
+        }
+        Some("strip") => route.strip_prefix = Some(true),
+        Some(rewrite) => route.rewrite = Some(rewrite.to_string()),
+        None => {}
+    }
+    if parts.next().is_some() {
+        return Err(format!("Route {} has too many parts", s));
+    }
+    Ok(route)
+}
+
+/// What to do when a site's listener fails to start or stops with an error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Report the failure and leave the site down
+    #[default]
+    Never,
+    /// Try again with exponential backoff
+    OnFailure,
+}
+
+impl std::str::FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "on-failure" => Ok(Self::OnFailure),
+            _ => Err(format!("Unknown restart policy: {} (expected never or on-failure)", s)),
+        }
+    }
+}
+
+/// Parse a size in bytes such as `1048576`, `512KB`, `10MB` or `1GB`.
+/// Units are binary (1KB = 1024 bytes) and case-insensitive.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid size: {}", s))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return Err(format!("Unknown size unit in {} (expected KB, MB or GB)", s)),
+    };
+    number.checked_mul(multiplier).ok_or_else(|| format!("Size too large: {}", s))
+}
+
+/// Accept sizes in config files either as a number of bytes or as a string
+/// understood by [`parse_size`].
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Text(text)) => parse_size(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Error pages are keyed by status code, which TOML tables can only hold as
+/// strings.
+fn deserialize_error_pages<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<BTreeMap<u16, PathBuf>>, D::Error> {
+    let Some(pages) = Option::<BTreeMap<String, PathBuf>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    pages
+        .into_iter()
+        .map(|(status, file)| match status.parse() {
+            Ok(status) => Ok((status, file)),
+            Err(_) => Err(serde::de::Error::custom(format!("Invalid error page status: {}", status))),
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+pub fn parse_site_config(s: &str) -> Result<SiteConfig, String> {
+    // Format: name:root:port[:https|:h2c][:proxy=PORT][:host=NAME]...[:default][:restart=POLICY]
+    //         [:max-request-body=SIZE][:max-response-body=SIZE][:route=PATTERN>TARGET]...
+    //         [:tls-cert=FILE][:tls-key=FILE][:cert-days=DAYS][:key-type=TYPE]
+    //         [:redirect-http=PORT][:redirect-plaintext][:spa|:fallback=FILE]
+    //         [:no-compression][:cache-dev][:listing][:error-page=CODE=FILE]...
+    let parts: Vec<&str> = s.split(':').collect();
+    
+    if parts.len() < 3 {
+        return Err("Site format should be: name:root:port[:https|:h2c][:proxy=PORT][:host=NAME][:default][:restart=POLICY][:max-request-body=SIZE][:max-response-body=SIZE][:route=PATTERN>TARGET][:tls-cert=FILE][:tls-key=FILE][:cert-days=DAYS][:key-type=TYPE][:redirect-http=PORT][:redirect-plaintext][:spa|:fallback=FILE][:no-compression][:cache-dev][:listing][:error-page=CODE=FILE]".to_string());
+    }
+
+    let name = parts[0].to_string();
+    let root = PathBuf::from(parts[1]);
+    let port: u16 = parts[2].parse().map_err(|_| "Invalid port number".to_string())?;
+    
+    let mut https = false;
+    let mut h2c = false;
+    let mut redirect_http_port = None;
+    let mut redirect_plaintext = false;
+    let mut proxy_to = None;
+    let mut hostnames = Vec::new();
+    let mut default_host = false;
+    let mut restart = RestartPolicy::Never;
+    let mut max_request_body = None;
+    let mut max_response_body = None;
+    let mut routes = Vec::new();
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut cert_days = None;
+    let mut key_type = None;
+    let mut fallback_file = None;
+    let mut directory_listing = false;
+    let mut error_pages = BTreeMap::new();
+    let mut compression = CompressionConfig::default();
+    let mut cache = CacheConfig::default();
+    
+    // Parse optional flags
+    for part in &parts[3..] {
+        match *part {
+            "https" => https = true,
+            "h2c" => h2c = true,
+            "redirect-plaintext" => redirect_plaintext = true,
+            "no-compression" => compression.enabled = Some(false),
+            "cache-dev" => cache.dev = Some(true),
+            "listing" => directory_listing = true,
+            part if part.starts_with("error-page=") => {
+                let (status, file) = parse_error_page(&part[11..])?;
+                error_pages.insert(status, file);
+            }
+            "spa" => fallback_file = Some(PathBuf::from(DEFAULT_FALLBACK_FILE)),
+            part if part.starts_with("fallback=") => fallback_file = Some(PathBuf::from(&part[9..])),
+            part if part.starts_with("redirect-http=") => {
+                let port = part[14..].parse::<u16>()
+                    .map_err(|_| "Invalid redirect port number".to_string())?;
+                redirect_http_port = Some(port);
+            }
+            part if part.starts_with("proxy=") => {
+                let proxy_port = part[6..].parse::<u16>()
+                    .map_err(|_| "Invalid proxy port number".to_string())?;
+                proxy_to = Some(proxy_port);
+            }
+            "default" => default_host = true,
+            part if part.starts_with("host=") => {
+                let hostname = &part[5..];
+                if hostname.is_empty() {
+                    return Err("Empty host name".to_string());
+                }
+                hostnames.push(hostname.to_string());
+            }
+            part if part.starts_with("restart=") => restart = part[8..].parse()?,
+            part if part.starts_with("max-request-body=") => max_request_body = Some(parse_size(&part[17..])?),
+            part if part.starts_with("max-response-body=") => max_response_body = Some(parse_size(&part[18..])?),
+            part if part.starts_with("route=") => routes.push(parse_route(&part[6..])?),
+            part if part.starts_with("tls-cert=") => tls_cert = Some(PathBuf::from(&part[9..])),
+            part if part.starts_with("tls-key=") => tls_key = Some(PathBuf::from(&part[8..])),
+            part if part.starts_with("cert-days=") => {
+                cert_days = Some(part[10..].parse().map_err(|_| "Invalid number of certificate days".to_string())?);
+            }
+            part if part.starts_with("key-type=") => key_type = Some(part[9..].parse()?),
+            _ => return Err(format!("Unknown site option: {}", part)),
+        }
+    }
+
+    if !root.exists() {
+        return Err(format!("Root directory does not exist: {}", root.display()));
+    }
+
+    let site = SiteConfig {
+        name,
+        root,
+        port,
+        https,
+        h2c,
+        redirect_http_port,
+        redirect_plaintext,
+        proxy_to,
+        hostnames,
+        default_host,
+        restart,
+        max_restarts: None,
+        max_request_body,
+        max_response_body,
+        upstreams: BTreeMap::new(),
+        routes,
+        headers: HeadersConfig::default(),
+        cors: None,
+        fallback_file,
+        directory_listing,
+        error_pages,
+        compression,
+        cache,
+        tls_cert,
+        tls_key,
+        cert_days,
+        key_type,
+    };
+    site.validate()?;
+    Ok(site)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MultiSiteConfig {
+    pub sites: Vec<ConfigSite>,
+    pub host: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigSite {
+    pub name: String,
+    pub root: PathBuf,
+    pub port: u16,
+    pub https: Option<bool>,
+    /// Also accept HTTP/2 without TLS from clients that know the server
+    /// speaks it (prior knowledge), e.g. a front proxy or `curl --http2-prior-knowledge`
+    pub h2c: Option<bool>,
+    /// Plain HTTP port that redirects to the site's HTTPS URL
+    pub redirect_http_port: Option<u16>,
+    /// Answer plain HTTP requests sent to the HTTPS port with a redirect
+    /// instead of failing the TLS handshake
+    pub redirect_plaintext: Option<bool>,
+    pub proxy_to: Option<u16>,
+    /// Host names this site answers to when it shares its port with others
+    pub hostnames: Option<Vec<String>>,
+    /// Serve requests for unknown host names on this port
+    pub default: Option<bool>,
+    /// `never` (default) or `on-failure`
+    pub restart: Option<RestartPolicy>,
+    /// Give up after this many restarts in a row (unlimited if unset). A
+    /// listener that stays up for a minute starts counting again from zero
+    pub max_restarts: Option<u32>,
+    /// Largest request body forwarded to the proxy backend, e.g. `10MB`
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_request_body: Option<u64>,
+    /// Largest response body passed back from the proxy backend
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_response_body: Option<u64>,
+    /// Named backends for routes to proxy to
+    pub upstreams: Option<BTreeMap<String, UpstreamConfig>>,
+    /// Routing rules, tried in order
+    pub routes: Option<Vec<RouteConfig>>,
+    /// Security and custom response headers
+    pub headers: Option<HeadersConfig>,
+    /// Cross-origin access to the site
+    pub cors: Option<CorsConfig>,
+    /// Single-page app: serve `index.html` for page requests to paths that
+    /// aren't files, so client-side routes can be deep-linked
+    pub spa: Option<bool>,
+    /// Document served instead of `index.html` in SPA mode, relative to
+    /// `root`; setting it turns SPA mode on
+    pub fallback_file: Option<PathBuf>,
+    /// List the files of folders without an `index.html`
+    pub directory_listing: Option<bool>,
+    /// Pages shown to browsers instead of the server's own error responses,
+    /// by status code, relative to `root`, e.g. `{ 404 = "404.html" }`
+    #[serde(default, deserialize_with = "deserialize_error_pages")]
+    pub error_pages: Option<BTreeMap<u16, PathBuf>>,
+    /// Response compression
+    pub compression: Option<CompressionConfig>,
+    /// Browser caching of static files
+    pub cache: Option<CacheConfig>,
+    /// Certificate to serve instead of one from the local CA (PEM, may
+    /// include the chain). Reloaded when the file changes.
+    pub tls_cert: Option<PathBuf>,
+    /// Private key of `tls_cert` (PEM)
+    pub tls_key: Option<PathBuf>,
+    /// Days the certificate from the local CA is valid for (397 if unset)
+    pub cert_days: Option<u32>,
+    /// Key of the certificate from the local CA: `ecdsa-p256` (default),
+    /// `ecdsa-p384`, `rsa-2048` or `rsa-4096`. RSA keys are signed by the
+    /// RSA local CA, see `server-cli cert ca show --rsa`
+    pub key_type: Option<KeyAlgorithm>,
+}
+
+impl From<ConfigSite> for SiteConfig {
+    fn from(config_site: ConfigSite) -> Self {
+        Self {
+            name: config_site.name,
+            root: config_site.root,
+            port: config_site.port,
+            https: config_site.https.unwrap_or(false),
+            h2c: config_site.h2c.unwrap_or(false),
+            redirect_http_port: config_site.redirect_http_port,
+            redirect_plaintext: config_site.redirect_plaintext.unwrap_or(false),
+            proxy_to: config_site.proxy_to,
+            hostnames: config_site.hostnames.unwrap_or_default(),
+            default_host: config_site.default.unwrap_or(false),
+            restart: config_site.restart.unwrap_or_default(),
+            max_restarts: config_site.max_restarts,
+            max_request_body: config_site.max_request_body,
+            max_response_body: config_site.max_response_body,
+            upstreams: config_site.upstreams.unwrap_or_default(),
+            routes: config_site.routes.unwrap_or_default(),
+            headers: config_site.headers.unwrap_or_default(),
+            cors: config_site.cors,
+            fallback_file: config_site
+                .fallback_file
+                .or_else(|| config_site.spa.unwrap_or(false).then(|| PathBuf::from(DEFAULT_FALLBACK_FILE))),
+            directory_listing: config_site.directory_listing.unwrap_or(false),
+            error_pages: config_site.error_pages.unwrap_or_default(),
+            compression: config_site.compression.unwrap_or_default(),
+            cache: config_site.cache.unwrap_or_default(),
+            tls_cert: config_site.tls_cert,
+            tls_key: config_site.tls_key,
+            cert_days: config_site.cert_days,
+            key_type: config_site.key_type,
+        }
+    }
+}
+
+impl SiteConfig {
+    /// Build the per-site server configuration used by the router.
+    pub fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            name: self.name.clone(),
+            root_dir: self.root.clone(),
+            https_enabled: self.https,
+            proxy_port: self.proxy_to,
+            max_request_body: self.max_request_body,
+            max_response_body: self.max_response_body,
+            upstreams: self.upstreams.clone(),
+            routes: self.routes.clone(),
+            headers: self.headers.clone(),
+            cors: self.cors.clone(),
+            fallback_file: self.fallback_file.as_ref().map(|file| self.root.join(file)),
+            directory_listing: self.directory_listing,
+            error_pages: self.error_pages.iter().map(|(status, file)| (*status, self.root.join(file))).collect(),
+            compression: self.compression.clone(),
+            cache: self.cache.clone(),
+        }
+    }
+
+    /// Check the parts of the site that are only used once it's served, so
+    /// mistakes are reported when the config is loaded.
+    pub fn validate(&self) -> Result<(), String> {
+        let upstreams = compile_upstreams(&self.upstreams)?;
+        Routes::compile(&self.routes, &upstreams)?;
+        HeaderPolicy::compile(&self.headers, self.https)?;
+        cors_layer(self.cors.as_ref())?;
+        compression_layer(&self.compression)?;
+        CachePolicy::compile(&self.cache)?;
+        if self.h2c && self.https {
+            return Err("h2c is for plain HTTP sites, HTTPS sites negotiate HTTP/2 on their own".to_string());
+        }
+        self.validate_tls_files()?;
+        self.validate_cert_options()?;
+        self.validate_redirects()?;
+        if let Some(file) = &self.fallback_file {
+            validate_site_file(file, "fallback_file")?;
+        }
+        for (status, file) in &self.error_pages {
+            if !(400..=599).contains(status) {
+                return Err(format!("error_pages are for 4xx and 5xx statuses, not {}", status));
+            }
+            validate_site_file(file, "error_pages")?;
+        }
+        Ok(())
+    }
+
+    fn validate_redirects(&self) -> Result<(), String> {
+        if (self.redirect_http_port.is_some() || self.redirect_plaintext) && !self.https {
+            return Err("redirect_http_port and redirect_plaintext need https".to_string());
+        }
+        if self.redirect_http_port == Some(self.port) {
+            return Err(format!("redirect_http_port can't be the site's own port {}", self.port));
+        }
+        Ok(())
+    }
+
+    fn validate_tls_files(&self) -> Result<(), String> {
+        let Some(files) = self.cert_files()? else {
+            return Ok(());
+        };
+        if !self.https {
+            return Err("tls_cert and tls_key need https".to_string());
+        }
+        #[cfg(feature = "ssl")]
+        crate::server::tls::load_cert_files(&files)?;
+        #[cfg(not(feature = "ssl"))]
+        let _ = files;
+        Ok(())
+    }
+
+    fn validate_cert_options(&self) -> Result<(), String> {
+        if self.cert_days.is_none() && self.key_type.is_none() {
+            return Ok(());
+        }
+        if !self.https {
+            return Err("cert_days and key_type need https".to_string());
+        }
+        if self.tls_cert.is_some() {
+            return Err("cert_days and key_type only apply to certificates from the local CA, not tls_cert".to_string());
+        }
+        match self.cert_days {
+            Some(days) if !(1..=MAX_CERT_DAYS).contains(&days) => {
+                Err(format!("cert_days must be between 1 and {}", MAX_CERT_DAYS))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// How the local CA issues the site's certificate.
+    pub fn cert_options(&self) -> CertOptions {
+        let defaults = CertOptions::default();
+        CertOptions {
+            lifetime_days: self.cert_days.unwrap_or(defaults.lifetime_days),
+            key_algorithm: self.key_type.unwrap_or(defaults.key_algorithm),
+        }
+    }
+
+    /// The site's own certificate, if it has one.
+    pub fn cert_files(&self) -> Result<Option<CertFiles>, String> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(CertFiles { cert: cert.clone(), key: key.clone() })),
+            (None, None) => Ok(None),
+            _ => Err("tls_cert and tls_key must be given together".to_string()),
+        }
+    }
+
+    pub fn protocol(&self) -> &'static str {
+        if self.https { "https" } else { "http" }
+    }
+
+    /// URLs the site can be reached at.
+    pub fn urls(&self, network: &NetworkInfo) -> SiteUrls {
+        let protocol = self.protocol();
+        let address = |host: &dyn std::fmt::Display| format!("{}://{}:{}", protocol, host, self.port);
+        let hosts = self
+            .hostnames
+            .iter()
+            .filter(|hostname| !hostname.starts_with("*."))
+            .map(|hostname| address(hostname))
+            .collect();
+
+        // Plain addresses only reach a site that doesn't rely on its host names
+        if !self.hostnames.is_empty() && !self.default_host {
+            return SiteUrls { hosts, ..Default::default() };
+        }
+
+        SiteUrls {
+            local: Some(address(&"localhost")),
+            network: network.local_ip.map(|ip| match ip {
+                IpAddr::V6(ip) => address(&format!("[{}]", ip)),
+                IpAddr::V4(ip) => address(&ip),
+            }),
+            internet: network.public_ip.as_ref().map(|ip| address(ip)),
+            hosts,
+        }
+    }
+}
+
+/// Where a site can be reached, by kind of address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SiteUrls {
+    pub local: Option<String>,
+    pub network: Option<String>,
+    pub internet: Option<String>,
+    pub hosts: Vec<String>,
+}
+
+/// Sites that are served from the same listener.
+#[derive(Debug, Clone)]
+pub struct SiteGroup {
+    pub port: u16,
+    pub https: bool,
+    pub h2c: bool,
+    /// Companion plain HTTP port redirecting to this one
+    pub redirect_http_port: Option<u16>,
+    pub redirect_plaintext: bool,
+    pub sites: Vec<SiteConfig>,
+}
+
+impl SiteGroup {
+    /// How the group's listener talks to clients.
+    pub fn listener_protocol(&self, network: &NetworkInfo) -> ListenerProtocol {
+        ListenerProtocol {
+            tls: self.tls_config(network),
+            h2c: self.h2c,
+            plaintext: self.redirect_plaintext.then(|| redirect_to_https(self.port)),
+        }
+    }
+
+    /// Whether the listener running this group can serve `other` as well,
+    /// so changes only need a new router.
+    pub fn same_listener(&self, other: &SiteGroup, network: &NetworkInfo) -> bool {
+        self.h2c == other.h2c
+            && self.redirect_http_port == other.redirect_http_port
+            && self.redirect_plaintext == other.redirect_plaintext
+            && self.tls_config(network) == other.tls_config(network)
+    }
+
+    /// The certificates the group's listener needs when it uses HTTPS, one
+    /// per site. A site without its own certificate files gets one from the
+    /// local CA, stored as [`CertOptions::store_name`] and covering its host
+    /// names, localhost and the machine's addresses, so it can be opened
+    /// from other devices on the LAN. Clients that send no known server
+    /// name get the default site's certificate.
+    pub fn tls_config(&self, network: &NetworkInfo) -> Option<TlsConfig> {
+        if !self.https {
+            return None;
+        }
+
+        let certs = self
+            .sites
+            .iter()
+            .map(|site| {
+                let mut names = site.hostnames.clone();
+                names.extend(["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()]);
+                names.extend(network.local_ips.iter().map(IpAddr::to_string));
+                let mut seen = HashSet::new();
+                names.retain(|name| seen.insert(name.to_ascii_lowercase()));
+
+                let options = site.cert_options();
+                SiteCert {
+                    hostnames: site.hostnames.clone(),
+                    name: options.store_name(&site.hostnames),
+                    names,
+                    options,
+                    files: site.cert_files().ok().flatten(),
+                }
+            })
+            .collect();
+        let default = self.sites.iter().position(|site| site.default_host).unwrap_or(0);
+
+        Some(TlsConfig { certs, default })
+    }
+}
+
+/// Group sites by port. Site names must be unique. A port may only be shared
+/// by sites that all declare `hostnames`, agree on HTTPS and have at most one
+/// default site.
+pub fn group_sites_by_port(sites: &[SiteConfig]) -> Result<Vec<SiteGroup>, String> {
+    let mut names = HashSet::new();
+    for site in sites {
+        if !names.insert(site.name.as_str()) {
+            return Err(format!("Site name '{}' is used more than once", site.name));
+        }
+    }
+
+    let mut by_port: BTreeMap<u16, Vec<SiteConfig>> = BTreeMap::new();
+    for site in sites {
+        by_port.entry(site.port).or_default().push(site.clone());
+    }
+
+    let mut groups = Vec::new();
+    for (port, sites) in by_port {
+        if sites.len() > 1 {
+            if let Some(site) = sites.iter().find(|s| s.hostnames.is_empty()) {
+                return Err(format!(
+                    "Port conflict: Multiple sites trying to use port {} and site '{}' has no hostnames to tell them apart",
+                    port, site.name
+                ));
+            }
+            if sites.iter().any(|s| s.https != sites[0].https || s.h2c != sites[0].h2c) {
+                return Err(format!("Sites sharing port {} must all use the same protocol", port));
+            }
+            if sites.iter().filter(|s| s.default_host).count() > 1 {
+                return Err(format!("Only one site on port {} can be the default", port));
+            }
+            let mut seen = HashSet::new();
+            for hostname in sites.iter().flat_map(|s| &s.hostnames) {
+                if !seen.insert(hostname.to_ascii_lowercase()) {
+                    return Err(format!("Host name {} is used by more than one site on port {}", hostname, port));
+                }
+            }
+            let redirect_ports: HashSet<u16> = sites.iter().filter_map(|s| s.redirect_http_port).collect();
+            if redirect_ports.len() > 1 {
+                return Err(format!("Sites sharing port {} must use the same redirect_http_port", port));
+            }
+        }
+        groups.push(SiteGroup {
+            port,
+            https: sites[0].https,
+            h2c: sites[0].h2c,
+            // Redirects go by Host header, so they cover every site on the port
+            redirect_http_port: sites.iter().find_map(|s| s.redirect_http_port),
+            redirect_plaintext: sites.iter().any(|s| s.redirect_plaintext),
+            sites,
+        });
+    }
+
+    let mut redirect_ports = HashSet::new();
+    for group in &groups {
+        let Some(redirect_port) = group.redirect_http_port else { continue };
+        if groups.iter().any(|other| other.port == redirect_port) || !redirect_ports.insert(redirect_port) {
+            return Err(format!(
+                "Port conflict: redirect_http_port {} of port {} is already in use",
+                redirect_port, group.port
+            ));
+        }
+    }
+
+    Ok(groups)
+}
+
+pub async fn load_sites_from_config(config_path: &Path) -> Result<Vec<SiteConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    if !config_path.exists() {
+        return Err(format!("Configuration file not found: {}", config_path.display()).into());
+    }
+    
+    let content = tokio::fs::read_to_string(config_path).await?;
+    let config: MultiSiteConfig = if config_path.extension().and_then(|s| s.to_str()) == Some("json") {
+        serde_json::from_str(&content)?
+    } else {
+        // Assume TOML
+        toml::from_str(&content)?
+    };
+    
+    // Validation reads the sites' files, certificates among them
+    tokio::task::spawn_blocking(move || {
+        let mut sites = Vec::new();
+        for config_site in config.sites {
+            validate_directory(&config_site.root)?;
+            let site: SiteConfig = config_site.into();
+            site.validate().map_err(|e| format!("Site {}: {}", site.name, e))?;
+            sites.push(site);
+        }
+        Ok(sites)
+    })
+    .await?
+}
+
+pub fn validate_directory(root: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !root.exists() {
+        return Err(format!("Root directory does not exist: {}", root.display()).into());
+    }
+    if !root.is_dir() {
+        return Err(format!("Root path is not a directory: {}", root.display()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &str, port: u16, hostnames: &[&str]) -> SiteConfig {
+        SiteConfig {
+            name: name.to_string(),
+            root: PathBuf::from("."),
+            port,
+            hostnames: hostnames.iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_site_with_hostnames() {
+        let site = parse_site_config(".:.:80:host=blog.local:host=www.blog.local:default").unwrap();
+        assert_eq!(site.port, 80);
+        assert_eq!(site.hostnames, vec!["blog.local", "www.blog.local"]);
+        assert!(site.default_host);
+
+        assert!(parse_site_config(".:.:80:host=").is_err());
+    }
+
+    #[test]
+    fn test_parse_restart_policy() {
+        assert_eq!(parse_site_config(".:.:80").unwrap().restart, RestartPolicy::Never);
+        assert_eq!(parse_site_config(".:.:80:restart=on-failure").unwrap().restart, RestartPolicy::OnFailure);
+        assert!(parse_site_config(".:.:80:restart=always").is_err());
+
+        let site: ConfigSite = toml::from_str("name = 'a'\nroot = '.'\nport = 80\nrestart = 'on-failure'\nmax_restarts = 3").unwrap();
+        let site = SiteConfig::from(site);
+        assert_eq!(site.restart, RestartPolicy::OnFailure);
+        assert_eq!(site.max_restarts, Some(3));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("512KB"), Ok(512 * 1024));
+        assert_eq!(parse_size("10mb"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1 GB"), Ok(1 << 30));
+        assert!(parse_size("10TB").is_err());
+        assert!(parse_size("MB").is_err());
+
+        let site = parse_site_config(".:.:80:proxy=3000:max-request-body=1MB").unwrap();
+        assert_eq!(site.max_request_body, Some(1 << 20));
+
+        let site: ConfigSite = toml::from_str("name = 'a'\nroot = '.'\nport = 80\nmax_request_body = '2KB'\nmax_response_body = 100").unwrap();
+        assert_eq!(site.max_request_body, Some(2048));
+        assert_eq!(site.max_response_body, Some(100));
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let site = parse_site_config(".:.:80:route=/api>proxy=3000:route=/assets/*.js>root=.:route=~^/old/(.*)>redirect=/new/$1>301").unwrap();
+        assert_eq!(site.routes.len(), 3);
+        assert_eq!(site.routes[0], RouteConfig { path: Some("/api".into()), proxy: Some(ProxyTarget::Port(3000)), ..Default::default() });
+        assert_eq!(site.routes[1].glob.as_deref(), Some("/assets/*.js"));
+        assert_eq!(site.routes[2].regex.as_deref(), Some("^/old/(.*)"));
+        assert_eq!(site.routes[2].status, Some(301));
+
+        assert!(parse_site_config(".:.:80:route=/api").is_err());
+        assert!(parse_site_config(".:.:80:route=/api>backend=3000").is_err());
+
+        let config = "name = 'a'\nroot = '.'\nport = 80\n[[routes]]\npath = '/api'\nproxy = 3000\n[[routes]]\nglob = '/*.md'\nredirect = '/docs'";
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert_eq!(site.routes[1].redirect.as_deref(), Some("/docs"));
+    }
+
+    #[test]
+    fn test_named_upstreams() {
+        let config = r#"
+            name = "app"
+            root = "."
+            port = 80
+            [upstreams.auth]
+            url = "https://192.168.1.20:4000"
+            [[routes]]
+            path = "/api"
+            proxy = 3000
+            strip_prefix = true
+            [[routes]]
+            path = "/auth"
+            proxy = "auth"
+            rewrite = "/v2"
+        "#;
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert_eq!(site.upstreams["auth"].url.as_deref(), Some("https://192.168.1.20:4000"));
+        assert_eq!(site.routes[0].proxy, Some(ProxyTarget::Port(3000)));
+        assert_eq!(site.routes[1].proxy, Some(ProxyTarget::Upstream("auth".to_string())));
+        assert!(site.validate().is_ok());
+
+        let mut unknown = site.clone();
+        unknown.routes[1].proxy = Some(ProxyTarget::Upstream("missing".to_string()));
+        assert!(unknown.validate().is_err());
+
+        let route = parse_route("/auth>proxy=auth>/v2").unwrap();
+        assert_eq!(route.rewrite.as_deref(), Some("/v2"));
+        assert_eq!(parse_route("/api>proxy=3000>strip").unwrap().strip_prefix, Some(true));
+    }
+
+    #[test]
+    fn test_headers_policy() {
+        let config = r#"
+            name = "app"
+            root = "."
+            port = 443
+            https = true
+            [headers]
+            hsts = "max-age=31536000"
+            content_security_policy = "default-src 'self'"
+            remove = ["x-powered-by"]
+            [headers.custom]
+            X-Env = "dev"
+        "#;
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert_eq!(site.headers.hsts.as_deref(), Some("max-age=31536000"));
+        assert_eq!(site.headers.custom.as_ref().unwrap()["X-Env"], "dev");
+        assert!(site.validate().is_ok());
+
+        assert!(SiteConfig { https: false, ..site }.validate().is_err());
+        assert!(toml::from_str::<ConfigSite>("name = 'a'\nroot = '.'\nport = 80\n[headers]\nxfo = 'DENY'").is_err());
+    }
+
+    #[test]
+    fn test_spa_fallback() {
+        let site = parse_site_config("app:.:8080:spa").unwrap();
+        assert_eq!(site.fallback_file, Some(PathBuf::from("index.html")));
+        let site = parse_site_config("app:.:8080:fallback=app.html").unwrap();
+        assert_eq!(site.server_config().fallback_file, Some(PathBuf::from("./app.html")));
+        assert!(parse_site_config("app:.:8080:fallback=../secret.html").is_err());
+
+        let config = "name = 'app'\nroot = '.'\nport = 80\nspa = true";
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert_eq!(site.fallback_file, Some(PathBuf::from("index.html")));
+    }
+
+    #[test]
+    fn test_compression_settings() {
+        let site = parse_site_config("app:.:8080:no-compression").unwrap();
+        assert_eq!(site.compression.enabled, Some(false));
+
+        let config = r#"
+            name = "app"
+            root = "."
+            port = 80
+            [compression]
+            br = false
+            min_size = "2KB"
+            mime_types = ["text/*", "application/json"]
+        "#;
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert_eq!(site.compression.min_size, Some(2048));
+        assert!(site.validate().is_ok());
+
+        let compression = CompressionConfig { mime_types: Some(vec!["json".to_string()]), ..Default::default() };
+        assert!(SiteConfig { compression, ..site }.validate().is_err());
+    }
+
+    #[test]
+    fn test_cache_settings() {
+        let site = parse_site_config("app:.:8080:cache-dev").unwrap();
+        assert_eq!(site.cache.dev, Some(true));
+
+        let config = r#"
+            name = "app"
+            root = "."
+            port = 80
+            [[cache.rules]]
+            glob = "/assets/**"
+            cache_control = "public, max-age=31536000, immutable"
+            [[cache.rules]]
+            extensions = ["html"]
+            cache_control = "no-cache"
+        "#;
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert_eq!(site.cache.rules.as_ref().map(Vec::len), Some(2));
+        assert!(site.validate().is_ok());
+
+        let rule = CacheRuleConfig { cache_control: "no-cache".to_string(), ..Default::default() };
+        let cache = CacheConfig { rules: Some(vec![rule]), ..Default::default() };
+        assert!(SiteConfig { cache, ..site }.validate().is_err());
+    }
+
+    #[test]
+    fn test_directory_listing() {
+        assert!(parse_site_config("app:.:8080:listing").unwrap().directory_listing);
+        assert!(!parse_site_config("app:.:8080").unwrap().directory_listing);
+
+        let config = "name = 'app'\nroot = '.'\nport = 80\ndirectory_listing = true";
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert!(site.server_config().directory_listing);
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let site = parse_site_config("app:.:8080:error-page=404=404.html:error-page=502=down.html").unwrap();
+        assert_eq!(site.error_pages.get(&502), Some(&PathBuf::from("down.html")));
+        assert!(parse_site_config("app:.:8080:error-page=200=ok.html").is_err());
+        assert!(parse_site_config("app:.:8080:error-page=404=/etc/passwd").is_err());
+        assert!(parse_site_config("app:.:8080:error-page=404").is_err());
+
+        let config = "name = 'app'\nroot = 'site'\nport = 80\nerror_pages = { 404 = '404.html' }";
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        let server = site.server_config();
+        assert_eq!(server.error_pages.get(&404), Some(&PathBuf::from("site/404.html")));
+    }
+
+    #[test]
+    fn test_cors_settings() {
+        let config = r#"
+            name = "app"
+            root = "."
+            port = 80
+            [cors]
+            allow_origins = ["http://localhost:5173"]
+            allow_credentials = true
+            max_age = 600
+        "#;
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        let cors = site.cors.clone().unwrap();
+        assert_eq!(cors.allow_origins, Some(vec!["http://localhost:5173".to_string()]));
+        assert!(site.validate().is_ok());
+
+        let wildcard = CorsConfig { allow_origins: Some(vec!["*".to_string()]), ..cors };
+        assert!(SiteConfig { cors: Some(wildcard), ..site }.validate().is_err());
+    }
+
+    #[test]
+    fn test_balanced_upstreams() {
+        let config = r#"
+            name = "app"
+            root = "."
+            port = 80
+            [upstreams.api]
+            urls = ["http://10.0.0.5:4000", "http://10.0.0.6:4000"]
+            strategy = "least-connections"
+            health_check = { path = "/ping", interval = 5 }
+            [[routes]]
+            path = "/api"
+            proxy = "api"
+        "#;
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        let api = &site.upstreams["api"];
+        assert_eq!(api.urls.as_ref().map(Vec::len), Some(2));
+        assert_eq!(api.strategy, Some(BalanceStrategy::LeastConnections));
+        assert_eq!(api.health_check.as_ref().and_then(|check| check.interval), Some(5));
+        assert!(site.validate().is_ok());
+
+        let mut both = site.clone();
+        both.upstreams.get_mut("api").unwrap().url = Some("http://10.0.0.7:4000".to_string());
+        assert!(both.validate().is_err());
+    }
+
+    #[test]
+    fn test_tls_files_need_each_other_and_https() {
+        let err = parse_site_config("app:.:8443:https:tls-cert=app.pem").unwrap_err();
+        assert!(err.contains("together"));
+        let err = parse_site_config("app:.:8443:tls-cert=app.pem:tls-key=app-key.pem").unwrap_err();
+        assert!(err.contains("need https"));
+    }
+
+    #[test]
+    fn test_cert_options() {
+        let site = parse_site_config("app:.:8443:https:cert-days=30:key-type=rsa-2048").unwrap();
+        assert_eq!(site.cert_options(), CertOptions { lifetime_days: 30, key_algorithm: KeyAlgorithm::Rsa2048 });
+        assert_eq!(parse_site_config("app:.:8443:https").unwrap().cert_options(), CertOptions::default());
+
+        let config = "name = 'a'\nroot = '.'\nport = 443\nhttps = true\nkey_type = 'ecdsa-p384'";
+        let site = SiteConfig::from(toml::from_str::<ConfigSite>(config).unwrap());
+        assert_eq!(site.cert_options().key_algorithm, KeyAlgorithm::EcdsaP384);
+
+        assert!(parse_site_config("app:.:8443:https:key-type=dsa").is_err());
+        assert!(parse_site_config("app:.:8443:https:cert-days=0").is_err());
+        assert!(parse_site_config("app:.:8080:cert-days=30").is_err());
+    }
+
+    #[test]
+    fn test_tls_config_has_a_certificate_per_site() {
+        let mut app = site("app", 8443, &["app.local", "*.app.local"]);
+        app.tls_cert = Some(PathBuf::from("app.pem"));
+        app.tls_key = Some(PathBuf::from("app-key.pem"));
+        let mut shop = site("shop", 8443, &["shop.local"]);
+        shop.default_host = true;
+        let group = SiteGroup {
+            port: 8443,
+            https: true,
+            h2c: false,
+            redirect_http_port: None,
+            redirect_plaintext: false,
+            sites: vec![app, shop],
+        };
+        let network = NetworkInfo {
+            local_ips: vec!["192.168.1.20".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let tls = group.tls_config(&network).unwrap();
+        assert_eq!(tls.default, 1);
+        assert_eq!(tls.certs[0].hostnames, ["app.local", "*.app.local"]);
+        assert_eq!(tls.certs[0].files.as_ref().map(|files| files.cert.clone()), Some(PathBuf::from("app.pem")));
+        assert_eq!(tls.certs[1].name, "shop.local");
+        assert_eq!(tls.certs[1].names, ["shop.local", "localhost", "127.0.0.1", "::1", "192.168.1.20"]);
+        assert!(SiteGroup { https: false, ..group.clone() }.tls_config(&network).is_none());
+
+        // Sites that only differ in their certificate settings don't share a file
+        let mut rsa = site("rsa", 8443, &[]);
+        rsa.key_type = Some(KeyAlgorithm::Rsa2048);
+        rsa.cert_days = Some(90);
+        let plain = site("plain", 8444, &[]);
+        let name = |site: SiteConfig| {
+            let group = SiteGroup { sites: vec![site], ..group.clone() };
+            group.tls_config(&network).unwrap().certs[0].name.clone()
+        };
+        assert_eq!(name(plain), "localhost");
+        assert_eq!(name(rsa), "localhost-rsa-2048-90d");
+        assert_eq!(name(site("multi", 8445, &["App.local", "api.app.local"])), "app.local+api.app.local");
+    }
+
+    #[test]
+    fn test_sites_share_port_with_hostnames() {
+        let groups = group_sites_by_port(&[
+            site("blog", 80, &["blog.local"]),
+            site("shop", 80, &["shop.local"]),
+            site("docs", 8081, &[]),
+        ])
+        .unwrap();
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].port, 80);
+        assert_eq!(groups[0].sites.len(), 2);
+    }
+
+    #[test]
+    fn test_port_conflicts_are_rejected() {
+        // A site without hostnames can't share its port
+        assert!(group_sites_by_port(&[site("a", 80, &["a.local"]), site("b", 80, &[])]).is_err());
+        // Host names must be unique per port
+        assert!(group_sites_by_port(&[site("a", 80, &["x.local"]), site("b", 80, &["X.local"])]).is_err());
+
+        let mut a = site("a", 80, &["a.local"]);
+        let mut b = site("b", 80, &["b.local"]);
+        a.default_host = true;
+        b.default_host = true;
+        assert!(group_sites_by_port(&[a.clone(), b.clone()]).is_err());
+
+        b.default_host = false;
+        b.https = true;
+        assert!(group_sites_by_port(&[a, b]).is_err());
+    }
+
+    #[test]
+    fn test_redirect_listeners() {
+        let parsed = parse_site_config("app:.:8443:https:redirect-http=8080:redirect-plaintext").unwrap();
+        assert_eq!(parsed.redirect_http_port, Some(8080));
+        assert!(parsed.redirect_plaintext);
+        assert!(parse_site_config("app:.:8080:redirect-http=8081").is_err());
+        assert!(parse_site_config("app:.:8443:https:redirect-http=8443").is_err());
+
+        let mut app = parsed;
+        app.hostnames = vec!["app.local".to_string()];
+        let mut shop = site("shop", 8443, &["shop.local"]);
+        shop.https = true;
+        let groups = group_sites_by_port(&[app.clone(), shop.clone()]).unwrap();
+        assert_eq!(groups[0].redirect_http_port, Some(8080));
+        assert!(groups[0].redirect_plaintext);
+
+        // The redirect port can't be taken by a site or another redirect
+        let mut docs = site("docs", 9443, &[]);
+        docs.https = true;
+        docs.redirect_http_port = Some(8080);
+        assert!(group_sites_by_port(&[app.clone(), docs]).is_err());
+        assert!(group_sites_by_port(&[app.clone(), site("web", 8080, &[])]).is_err());
+        shop.redirect_http_port = Some(8081);
+        assert!(group_sites_by_port(&[app, shop]).is_err());
+    }
+}
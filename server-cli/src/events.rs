@@ -0,0 +1,126 @@
+use serde::Serialize;
+use std::{
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::SiteUrls;
+
+/// Format of what server-cli writes to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputMode {
+    /// Human-readable log lines
+    Text,
+    /// Newline-delimited JSON events; log lines go to stderr
+    Json,
+}
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_output_mode(mode: OutputMode) {
+    JSON_OUTPUT.store(mode == OutputMode::Json, Ordering::Relaxed);
+}
+
+pub fn json_enabled() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Events emitted in `--output json` mode, one JSON object per line.
+///
+/// Every event has an `event` field with its snake_case name, a
+/// `timestamp_ms` field (milliseconds since the Unix epoch) and a `site`
+/// field naming the site it concerns (`null` for process-wide events).
+/// Fields are only ever added, never renamed or removed.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    SiteStarted {
+        site: &'a str,
+        port: u16,
+        https: bool,
+        urls: &'a SiteUrls,
+    },
+    SiteStopped {
+        site: &'a str,
+        port: u16,
+    },
+    /// The site's listener couldn't start or stopped with an error
+    SiteFailed {
+        site: &'a str,
+        port: u16,
+        error: &'a str,
+        /// Whether the listener will be started again by its restart policy
+        restarting: bool,
+    },
+    ProxyError {
+        site: &'a str,
+        method: &'a str,
+        path: &'a str,
+        upstream: &'a str,
+        error: &'a str,
+    },
+    Request {
+        site: &'a str,
+        method: &'a str,
+        path: &'a str,
+        status: u16,
+        duration_ms: u64,
+    },
+    Fatal {
+        site: Option<&'a str>,
+        error: &'a str,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+    timestamp_ms: u64,
+}
+
+/// Write an event to stdout when JSON output is enabled.
+pub fn emit(event: Event<'_>) {
+    if !json_enabled() {
+        return;
+    }
+
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let envelope = Envelope { event: &event, timestamp_ms };
+
+    if let Ok(line) = serde_json::to_string(&envelope) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_schema() {
+        let urls = SiteUrls {
+            local: Some("https://localhost:8443".to_string()),
+            ..Default::default()
+        };
+        let event = Event::SiteStarted { site: "blog", port: 8443, https: true, urls: &urls };
+        let value = serde_json::to_value(Envelope { event: &event, timestamp_ms: 1 }).unwrap();
+
+        assert_eq!(value["event"], "site_started");
+        assert_eq!(value["site"], "blog");
+        assert_eq!(value["urls"]["local"], "https://localhost:8443");
+        assert_eq!(value["urls"]["network"], serde_json::Value::Null);
+        assert_eq!(value["timestamp_ms"], 1);
+
+        let event = Event::Fatal { site: None, error: "boom" };
+        let value = serde_json::to_value(Envelope { event: &event, timestamp_ms: 1 }).unwrap();
+        assert_eq!(value["event"], "fatal");
+        assert_eq!(value["site"], serde_json::Value::Null);
+    }
+}
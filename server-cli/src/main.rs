@@ -1,33 +1,43 @@
-use axum::{
-    extract::Request,
-    http::{StatusCode, Uri},
-    routing::get,
-    Router,
-};
-use clap::Parser;
-use serde::{Deserialize, Serialize};
+use clap::{Parser, Subcommand};
 use std::{
+    collections::BTreeMap,
     net::SocketAddr,
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
-use tokio::{net::TcpListener, task::JoinHandle};
-use tower::util::ServiceExt;
-use tower_http::{
-    cors::CorsLayer,
-    services::ServeDir,
-    trace::TraceLayer,
-};
+use tokio_util::sync::CancellationToken;
 use tracing::{info, warn, error};
 
+mod cert;
+mod config;
+mod events;
 mod server;
 mod network;
 
-use server::{AppState, ServerConfig};
-use network::{get_public_ip, get_local_ips};
+use config::{
+    group_sites_by_port, load_sites_from_config, parse_error_page, parse_site_config, parse_size, validate_directory,
+    CacheConfig, CompressionConfig, HeadersConfig, RestartPolicy, SiteConfig, DEFAULT_FALLBACK_FILE,
+};
+use events::{Event, OutputMode};
+use server::{
+    admin::serve_admin,
+    build_router,
+    listener::{bind_listener, serve_listener, DrainOutcome},
+    redirect::spawn_redirect_listener,
+    shutdown::shutdown_signal,
+    ssl::KeyAlgorithm,
+    watch::{watch_file, POLL_INTERVAL},
+    AppState, SiteManager,
+};
+use network::{get_public_ip, get_local_ips, is_private_ip, NetworkInfo};
+
+/// Exit code when open connections had to be closed after the drain timeout
+const EXIT_DRAIN_TIMEOUT: i32 = 2;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(after_help = "Exit codes: 0 after a clean shutdown, 1 on errors, 2 when open connections were closed after the drain timeout")]
 struct Cli {
     /// Root directory to serve files from (single site mode)
     #[arg(short, long, value_name = "DIR", conflicts_with = "config")]
@@ -37,14 +47,74 @@ struct Cli {
     #[arg(short, long, default_value_t = 8080, conflicts_with = "config")]
     port: u16,
 
-    /// Enable HTTPS with self-signed certificate (single site mode)
+    /// Enable HTTPS with a certificate from the local CA (single site mode)
     #[arg(long, conflicts_with = "config")]
     https: bool,
 
+    /// Also accept HTTP/2 without TLS from clients with prior knowledge (single site mode)
+    #[arg(long, conflicts_with_all = ["config", "https"])]
+    h2c: bool,
+
+    /// Plain HTTP port that redirects to the HTTPS site (single site mode)
+    #[arg(long, value_name = "PORT", requires = "https", conflicts_with = "config")]
+    redirect_http_port: Option<u16>,
+
+    /// Redirect plain HTTP requests sent to the HTTPS port instead of failing them (single site mode)
+    #[arg(long, requires = "https", conflicts_with = "config")]
+    redirect_plaintext: bool,
+
+    /// Certificate to serve instead of one from the local CA, PEM (single site mode)
+    #[arg(long, value_name = "FILE", requires_all = ["https", "tls_key"], conflicts_with = "config")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of --tls-cert, PEM (single site mode)
+    #[arg(long, value_name = "FILE", requires = "tls_cert", conflicts_with = "config")]
+    tls_key: Option<PathBuf>,
+
+    /// Days the certificate from the local CA is valid for (single site mode)
+    #[arg(long, value_name = "DAYS", requires = "https", conflicts_with_all = ["config", "tls_cert"])]
+    cert_days: Option<u32>,
+
+    /// Key of the certificate from the local CA: ecdsa-p256, ecdsa-p384, rsa-2048 or rsa-4096 (single site mode)
+    #[arg(long, value_name = "TYPE", requires = "https", conflicts_with_all = ["config", "tls_cert"])]
+    key_type: Option<KeyAlgorithm>,
+
+    /// Serve index.html for page requests to paths that aren't files, for single-page apps (single site mode)
+    #[arg(long, conflicts_with = "config")]
+    spa: bool,
+
+    /// Document served instead of index.html in SPA mode, relative to the root (single site mode)
+    #[arg(long, value_name = "FILE", conflicts_with = "config")]
+    fallback_file: Option<PathBuf>,
+
+    /// List the files of folders without an index.html (single site mode)
+    #[arg(long, conflicts_with = "config")]
+    directory_listing: bool,
+
+    /// Page shown to browsers for an error status, CODE=FILE relative to the root, e.g. 404=404.html (single site mode)
+    #[arg(long, value_name = "CODE=FILE", value_parser = parse_error_page, conflicts_with = "config")]
+    error_page: Vec<(u16, PathBuf)>,
+
+    /// Don't compress responses (single site mode)
+    #[arg(long, conflicts_with = "config")]
+    no_compression: bool,
+
+    /// Dev mode caching: tell browsers not to store any files (single site mode)
+    #[arg(long, conflicts_with = "config")]
+    cache_dev: bool,
+
     /// Backend port to proxy non-file requests to (single site mode)
     #[arg(long, value_name = "PORT", conflicts_with = "config")]
     proxy_to: Option<u16>,
 
+    /// Largest request body forwarded to the backend, e.g. 10MB (single site mode)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "config")]
+    max_request_body: Option<u64>,
+
+    /// Largest response body passed back from the backend (single site mode)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "config")]
+    max_response_body: Option<u64>,
+
     /// Host to bind to
     #[arg(long, default_value = "0.0.0.0")]
     host: String,
@@ -56,196 +126,172 @@ struct Cli {
     /// Add a site (can be used multiple times)
     #[arg(long, value_parser = parse_site_config, conflicts_with = "config")]
     site: Vec<SiteConfig>,
-}
 
-#[derive(Debug, Clone)]
-struct SiteConfig {
-    name: String,
-    root: PathBuf,
-    port: u16,
-    https: bool,
-    proxy_to: Option<u16>,
-}
+    /// Don't reload the configuration file when it changes
+    #[arg(long, requires = "config")]
+    no_watch: bool,
 
-fn parse_site_config(s: &str) -> Result<SiteConfig, String> {
-    // Format: name:root:port[:https][:proxy=PORT]
-    let parts: Vec<&str> = s.split(':').collect();
-    
-    if parts.len() < 3 {
-        return Err("Site format should be: name:root:port[:https][:proxy=PORT]".to_string());
-    }
+    /// Address for the admin API used to manage running sites (e.g. 127.0.0.1:9000)
+    #[arg(long, value_name = "ADDR")]
+    admin: Option<SocketAddr>,
 
-    let name = parts[0].to_string();
-    let root = PathBuf::from(parts[1]);
-    let port: u16 = parts[2].parse().map_err(|_| "Invalid port number".to_string())?;
-    
-    let mut https = false;
-    let mut proxy_to = None;
-    
-    // Parse optional flags
-    for part in &parts[3..] {
-        match *part {
-            "https" => https = true,
-            part if part.starts_with("proxy=") => {
-                let proxy_port = part[6..].parse::<u16>()
-                    .map_err(|_| "Invalid proxy port number".to_string())?;
-                proxy_to = Some(proxy_port);
-            }
-            _ => return Err(format!("Unknown site option: {}", part)),
-        }
-    }
+    /// Token required by the admin API (generated when not given)
+    #[arg(long, value_name = "TOKEN", requires = "admin")]
+    admin_token: Option<String>,
 
-    if !root.exists() {
-        return Err(format!("Root directory does not exist: {}", root.display()));
-    }
+    /// Output format on stdout
+    #[arg(long, value_enum, default_value_t = OutputMode::Text)]
+    output: OutputMode,
 
-    Ok(SiteConfig {
-        name,
-        root,
-        port,
-        https,
-        proxy_to,
-    })
-}
+    /// Seconds to let open connections finish when shutting down or stopping a site
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    drain_timeout: u64,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MultiSiteConfig {
-    sites: Vec<ConfigSite>,
-    host: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ConfigSite {
-    name: String,
-    root: PathBuf,
-    port: u16,
-    https: Option<bool>,
-    proxy_to: Option<u16>,
-}
-
-impl From<ConfigSite> for SiteConfig {
-    fn from(config_site: ConfigSite) -> Self {
-        Self {
-            name: config_site.name,
-            root: config_site.root,
-            port: config_site.port,
-            https: config_site.https.unwrap_or(false),
-            proxy_to: config_site.proxy_to,
-        }
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the local CA and the certificates used by HTTPS sites
+    #[command(subcommand)]
+    Cert(cert::CertCommand),
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    tracing_subscriber::fmt()
+async fn main() {
+    let cli = Cli::parse();
+
+    // Initialize logging. In JSON mode stdout only carries events, and
+    // subcommands keep it for their own output.
+    let logging = tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "server_cli=info,tower_http=debug".into())
-        )
-        .init();
+        );
+    if cli.output == OutputMode::Json || cli.command.is_some() {
+        logging.with_writer(std::io::stderr).init();
+    } else {
+        logging.init();
+    }
+    events::set_output_mode(cli.output);
 
-    let cli = Cli::parse();
-    
+    if let Some(Command::Cert(command)) = cli.command {
+        if let Err(e) = cert::run(command) {
+            exit_with_error(&e.to_string());
+        }
+        return;
+    }
+
+    match run(cli).await {
+        Ok(DrainOutcome::Drained) => info!("👋 Server stopped"),
+        Ok(DrainOutcome::TimedOut) => {
+            warn!("👋 Server stopped, some connections were closed before finishing");
+            std::process::exit(EXIT_DRAIN_TIMEOUT);
+        }
+        Err(e) => exit_with_error(&e.to_string()),
+    }
+}
+
+async fn run(cli: Cli) -> Result<DrainOutcome, Box<dyn std::error::Error + Send + Sync>> {
     // Determine sites to run
     let sites = resolve_sites(&cli).await?;
     
-    if sites.is_empty() {
-        error!("No sites configured. Use --root for single site or --site for multiple sites or --config for config file");
-        std::process::exit(1);
+    if sites.is_empty() && cli.admin.is_none() {
+        exit_with_error("No sites configured. Use --root for single site or --site for multiple sites or --config for config file");
     }
     
     // Display network information
-    display_network_info(&sites).await;
+    let network = display_network_info(&sites).await;
     
     // Check for port conflicts
-    let mut used_ports = std::collections::HashSet::new();
-    for site in &sites {
-        if used_ports.contains(&site.port) {
-            error!("Port conflict: Multiple sites trying to use port {}", site.port);
-            std::process::exit(1);
-        }
-        used_ports.insert(site.port);
+    if let Err(e) = group_sites_by_port(&sites) {
+        exit_with_error(&e);
     }
     
-    if sites.len() == 1 {
+    if sites.len() == 1 && cli.config.is_none() && cli.admin.is_none() {
         // Single site mode - run directly
-        run_single_site(&sites[0], &cli.host).await?;
+        run_single_site(&sites[0], &cli, &network).await
     } else {
-        // Multi-site mode - spawn multiple servers
-        run_multi_sites(sites, &cli.host).await?;
+        // Multi-site mode - one server per port, managed at runtime
+        run_multi_sites(sites, &cli, network).await
     }
-    
-    Ok(())
 }
 
-async fn resolve_sites(cli: &Cli) -> Result<Vec<SiteConfig>, Box<dyn std::error::Error>> {
+fn exit_with_error(message: &str) -> ! {
+    error!("{}", message);
+    events::emit(Event::Fatal { site: None, error: message });
+    std::process::exit(1);
+}
+
+async fn resolve_sites(cli: &Cli) -> Result<Vec<SiteConfig>, Box<dyn std::error::Error + Send + Sync>> {
     if let Some(config_path) = &cli.config {
         // Load from config file
-        load_sites_from_config(config_path).await
+        Ok(load_sites_from_config(config_path).await?)
     } else if !cli.site.is_empty() {
         // Use CLI site arguments
         Ok(cli.site.clone())
     } else if let Some(root) = &cli.root {
         // Single site mode
         validate_directory(root)?;
-        Ok(vec![SiteConfig {
+        let site = SiteConfig {
             name: "main".to_string(),
             root: root.clone(),
             port: cli.port,
             https: cli.https,
+            h2c: cli.h2c,
+            redirect_http_port: cli.redirect_http_port,
+            redirect_plaintext: cli.redirect_plaintext,
             proxy_to: cli.proxy_to,
-        }])
+            hostnames: Vec::new(),
+            default_host: false,
+            restart: RestartPolicy::Never,
+            max_restarts: None,
+            max_request_body: cli.max_request_body,
+            max_response_body: cli.max_response_body,
+            upstreams: BTreeMap::new(),
+            routes: Vec::new(),
+            headers: HeadersConfig::default(),
+            cors: None,
+            fallback_file: cli
+                .fallback_file
+                .clone()
+                .or_else(|| cli.spa.then(|| PathBuf::from(DEFAULT_FALLBACK_FILE))),
+            directory_listing: cli.directory_listing,
+            error_pages: cli.error_page.iter().cloned().collect(),
+            compression: CompressionConfig {
+                enabled: cli.no_compression.then_some(false),
+                ..Default::default()
+            },
+            cache: CacheConfig {
+                dev: cli.cache_dev.then_some(true),
+                ..Default::default()
+            },
+            tls_cert: cli.tls_cert.clone(),
+            tls_key: cli.tls_key.clone(),
+            cert_days: cli.cert_days,
+            key_type: cli.key_type,
+        };
+        site.validate()?;
+        Ok(vec![site])
     } else {
         Ok(vec![])
     }
 }
 
-async fn load_sites_from_config(config_path: &PathBuf) -> Result<Vec<SiteConfig>, Box<dyn std::error::Error>> {
-    if !config_path.exists() {
-        return Err(format!("Configuration file not found: {}", config_path.display()).into());
-    }
-    
-    let content = tokio::fs::read_to_string(config_path).await?;
-    let config: MultiSiteConfig = if config_path.extension().and_then(|s| s.to_str()) == Some("json") {
-        serde_json::from_str(&content)?
-    } else {
-        // Assume TOML
-        toml::from_str(&content)?
-    };
-    
-    let mut sites = Vec::new();
-    for config_site in config.sites {
-        validate_directory(&config_site.root)?;
-        sites.push(config_site.into());
-    }
-    
-    Ok(sites)
-}
-
-fn validate_directory(root: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    if !root.exists() {
-        return Err(format!("Root directory does not exist: {}", root.display()).into());
-    }
-    if !root.is_dir() {
-        return Err(format!("Root path is not a directory: {}", root.display()).into());
-    }
-    Ok(())
-}
-
-async fn run_single_site(site: &SiteConfig, host: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let config = ServerConfig {
-        root_dir: site.root.clone(),
-        port: site.port,
-        host: host.to_string(),
-        https_enabled: site.https,
-        proxy_port: site.proxy_to,
-    };
-
-    let state = Arc::new(AppState::new(config));
+async fn run_single_site(
+    site: &SiteConfig,
+    cli: &Cli,
+    network: &NetworkInfo,
+) -> Result<DrainOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let host = cli.host.as_str();
+    let state = Arc::new(AppState::new(site.server_config()));
     let app = build_router(state.clone()).await?;
-    let addr: SocketAddr = format!("{}:{}", host, site.port).parse()?;
-    let listener = TcpListener::bind(addr).await?;
+    let listener = bind_listener(host, site.port).await?;
+    let redirect_listener = match site.redirect_http_port {
+        Some(redirect_port) => Some(bind_listener(host, redirect_port).await?),
+        None => None,
+    };
 
     let protocol = if site.https { "https" } else { "http" };
     info!("🚀 LocalHostify server starting...");
@@ -256,252 +302,140 @@ async fn run_single_site(site: &SiteConfig, host: &str) -> Result<(), Box<dyn st
     }
 
     info!("✅ Server ready! Press Ctrl+C to stop");
-
-    if site.https {
-        #[cfg(feature = "ssl")]
-        {
-            run_https_server(listener, app, state).await?;
-        }
-        #[cfg(not(feature = "ssl"))]
-        {
-            error!("HTTPS requested but SSL feature not enabled");
-            std::process::exit(1);
+    events::emit(Event::SiteStarted {
+        site: &site.name,
+        port: site.port,
+        https: site.https,
+        urls: &site.urls(network),
+    });
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
         }
-    } else {
-        axum::serve(listener, app).await?;
+    });
+
+    let group = group_sites_by_port(std::slice::from_ref(site))?.remove(0);
+    let drain_timeout = Duration::from_secs(cli.drain_timeout);
+    let redirect = redirect_listener
+        .map(|listener| spawn_redirect_listener(listener, site.port, shutdown.clone(), drain_timeout));
+    let outcome = serve_listener(listener, app, group.listener_protocol(network), shutdown, drain_timeout).await?;
+    if let Some(redirect) = redirect {
+        let _ = redirect.await;
     }
+    events::emit(Event::SiteStopped { site: &site.name, port: site.port });
 
-    Ok(())
+    Ok(outcome)
 }
 
-async fn run_multi_sites(sites: Vec<SiteConfig>, host: &str) -> Result<(), Box<dyn std::error::Error>> {
+async fn run_multi_sites(
+    sites: Vec<SiteConfig>,
+    cli: &Cli,
+    network: NetworkInfo,
+) -> Result<DrainOutcome, Box<dyn std::error::Error + Send + Sync>> {
     info!("🚀 LocalHostify multi-site server starting...");
     info!("📊 Running {} sites:", sites.len());
     
-    let mut handles: Vec<JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>> = Vec::new();
-    
-    for site in sites {
-        let host = host.to_string();
-        let handle = tokio::spawn(async move {
-            run_site_server(site, &host).await
-        });
-        handles.push(handle);
-    }
-    
-    info!("✅ All servers ready! Press Ctrl+C to stop");
-    
-    // Wait for all servers (or until one fails)
-    let (result, _index, _remaining) = futures::future::select_all(handles).await;
-    
-    match result {
-        Ok(Ok(())) => {
-            info!("Server completed successfully");
-            Ok(())
-        }
-        Ok(Err(e)) => {
-            error!("Server error: {}", e);
-            Err(e)
-        }
-        Err(e) => {
-            error!("Task join error: {}", e);
-            Err(e.into())
-        }
+    let manager = SiteManager::new(&cli.host, network, Duration::from_secs(cli.drain_timeout));
+    manager.apply(sites).await?;
+
+    // Failed sites only come back through a restart policy, a config reload
+    // or the admin API; without any of those there is nothing left to do
+    let can_recover = cli.admin.is_some() || (cli.config.is_some() && !cli.no_watch);
+    if !can_recover && manager.is_idle().await {
+        return Err("No site could be started".into());
     }
-}
-
-async fn run_site_server(site: SiteConfig, host: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let config = ServerConfig {
-        root_dir: site.root.clone(),
-        port: site.port,
-        host: host.to_string(),
-        https_enabled: site.https,
-        proxy_port: site.proxy_to,
-    };
-
-    let state = Arc::new(AppState::new(config));
-    let app = build_router(state.clone()).await.map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { 
-        Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
-    })?;
     
-    let addr: SocketAddr = format!("{}:{}", host, site.port).parse()
-        .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(e) })?;
-    let listener = TcpListener::bind(addr).await
-        .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(e) })?;
+    info!("✅ Servers ready! Press Ctrl+C to stop");
 
-    let protocol = if site.https { "https" } else { "http" };
-    info!("   📁 {} → {}://{}:{}", site.name, protocol, host, site.port);
-    
-    if let Some(proxy_port) = site.proxy_to {
-        info!("   🔄 {} proxying API → localhost:{}", site.name, proxy_port);
+    if let Some(config_path) = cli.config.clone().filter(|_| !cli.no_watch) {
+        info!("👀 Watching {} for changes", config_path.display());
+        tokio::spawn(watch_config(config_path, manager.clone()));
     }
 
-    if site.https {
-        #[cfg(feature = "ssl")]
-        {
-            run_https_server(listener, app, state).await
-                .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())) })?;
-        }
-        #[cfg(not(feature = "ssl"))]
-        {
-            return Err("HTTPS requested but SSL feature not enabled".into());
+    let admin_shutdown = CancellationToken::new();
+    if let Some(admin_addr) = cli.admin {
+        let token = cli.admin_token.clone().unwrap_or_else(|| {
+            let token = uuid::Uuid::new_v4().simple().to_string();
+            info!("🔑 Admin token: {}", token);
+            token
+        });
+        if !admin_addr.ip().is_loopback() {
+            warn!("⚠️  Admin API is reachable from other machines on {}", admin_addr);
         }
-    } else {
-        axum::serve(listener, app).await
-            .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(e) })?;
-    }
-
-    Ok(())
-}
-
-async fn build_router(state: Arc<AppState>) -> Result<Router, Box<dyn std::error::Error>> {
-    let mut router = Router::new()
-        .route("/health", get(health_check))
-        .route("/healthz", get(health_check))
-        .with_state(state.clone())
-        .layer(CorsLayer::permissive())
-        .layer(TraceLayer::new_for_http());
-
-    // Add static file serving
-    let serve_dir = ServeDir::new(&state.config.root_dir)
-        .append_index_html_on_directories(true);
-
-    // If proxy is configured, use fallback handler instead of service
-    if state.config.proxy_port.is_some() {
-        router = router.fallback(|req: Request| async move {
-            let uri = req.uri().clone();
-            
-            // Check if this looks like an API request (starts with /api or common paths)
-            if should_proxy(&uri) {
-                server::proxy_request(req, state).await
-            } else {
-                // For non-API requests, return a 404 and let the ServeDir handle it
-                // We'll add ServeDir as a separate fallback layer
-                Err(StatusCode::NOT_FOUND)
+        let listener = tokio::net::TcpListener::bind(admin_addr).await?;
+        let manager = manager.clone();
+        let shutdown = admin_shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_admin(listener, token, manager, shutdown).await {
+                error!("Admin API failed: {}", e);
             }
-        }).fallback_service(serve_dir);
-    } else {
-        router = router.fallback_service(serve_dir);
+        });
     }
-
-    Ok(router)
-}
-
-fn should_proxy(uri: &Uri) -> bool {
-    let path = uri.path();
-    // Proxy requests that look like API calls
-    path.starts_with("/api") 
-        || path.starts_with("/v1") 
-        || path.starts_with("/graphql")
-        || path.contains("/api/")
-        // But don't proxy requests for static assets
-        && !path.ends_with(".html")
-        && !path.ends_with(".css") 
-        && !path.ends_with(".js")
-        && !path.ends_with(".png")
-        && !path.ends_with(".jpg")
-        && !path.ends_with(".jpeg")
-        && !path.ends_with(".gif")
-        && !path.ends_with(".svg")
-        && !path.ends_with(".ico")
-        && !path.ends_with(".woff")
-        && !path.ends_with(".woff2")
-        && !path.ends_with(".ttf")
-        && !path.ends_with(".eot")
-}
-
-#[cfg(feature = "ssl")]
-async fn run_https_server(
-    listener: TcpListener,
-    app: Router,
-    _state: Arc<AppState>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use server::ssl::create_self_signed_cert;
-    use std::io::Cursor;
-    use tokio_rustls::{rustls, TlsAcceptor};
-
-    // Generate self-signed certificate
-    let cert_pem = create_self_signed_cert("localhost")?;
-    
-    // Parse certificate and key from PEM into the types rustls expects.
-    let mut cert_reader = Cursor::new(cert_pem.cert.as_bytes());
-    let cert_iter = rustls_pemfile::certs(&mut cert_reader);
-    let cert_chain: Vec<rustls::pki_types::CertificateDer> = 
-        cert_iter.collect::<Result<Vec<_>, _>>()
-            .map_err(|e| format!("failed reading cert pem: {}", e))?;
-
-    let mut key_reader = Cursor::new(cert_pem.key.as_bytes());
-    // Try private_key (generic) function first
-    let private_key = rustls_pemfile::private_key(&mut key_reader)
-        .map_err(|e| format!("failed reading private key: {}", e))?
-        .ok_or("No private key found in PEM")?;
-
-    let server_config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, private_key)
-        .map_err(|e| format!("failed to build rustls server config: {}", e))?;
-
-    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
     
-    info!("🔒 HTTPS enabled with self-signed certificate");
-    warn!("⚠️  Browsers will show a security warning for self-signed certificates");
-    
-    loop {
-        let (stream, _addr) = listener.accept().await?;
-        let tls_acceptor = tls_acceptor.clone();
-        let app = app.clone();
-
-        tokio::spawn(async move {
-            let tls_stream = match tls_acceptor.accept(stream).await {
-                Ok(tls_stream) => tls_stream,
-                Err(err) => {
-                    error!("Failed to establish TLS connection: {}", err);
-                    return;
-                }
-            };
+    // Failed sites are reported and restarted by the manager, so run until
+    // asked to stop
+    shutdown_signal().await;
 
-            let service = hyper::service::service_fn(move |req| {
-                app.clone().oneshot(req)
-            });
+    admin_shutdown.cancel();
+    Ok(manager.shutdown().await)
+}
 
-            if let Err(err) = hyper::server::conn::http1::Builder::new()
-                .serve_connection(hyper_util::rt::TokioIo::new(tls_stream), service)
-                .await
-            {
-                error!("Failed to serve HTTPS connection: {}", err);
+/// Reload the configuration file whenever it changes and apply the difference
+/// to the running sites. Invalid edits are reported and the previous
+/// configuration keeps running.
+async fn watch_config(config_path: PathBuf, manager: Arc<SiteManager>) {
+    let mut changes = watch_file(config_path.clone(), POLL_INTERVAL);
+
+    while changes.recv().await.is_some() {
+        info!("📝 Configuration changed, reloading {}", config_path.display());
+        let sites = match load_sites_from_config(&config_path).await {
+            Ok(sites) => sites,
+            Err(e) => {
+                error!("Failed to reload configuration: {}", e);
+                continue;
             }
-        });
+        };
+        if let Err(e) = manager.apply(sites).await {
+            error!("Failed to apply reloaded configuration: {}", e);
+        }
     }
 }
 
-async fn health_check() -> &'static str {
-    "LocalHostify server is healthy"
-}
 
-async fn display_network_info(sites: &[SiteConfig]) {
+async fn display_network_info(sites: &[SiteConfig]) -> NetworkInfo {
     info!("🔍 Detecting network configuration...");
     
     // Get local IPs
-    let local_ip = match get_local_ips().await {
+    let local_ips = match get_local_ips().await {
         Ok(local_ips) => {
             if local_ips.is_empty() {
                 warn!("No local IP addresses found");
-                None
             } else {
                 info!("💻 Local IP addresses:");
                 for ip in &local_ips {
                     info!("   • {}", ip);
                 }
-                Some(local_ips[0])
             }
+            local_ips
         }
         Err(e) => {
             warn!("Failed to detect local IPs: {}", e);
-            None
+            Vec::new()
         }
     };
+    // Prefer a LAN address for the "Network" URL over a public one
+    let local_ip = local_ips
+        .iter()
+        .find(|ip| is_private_ip(ip))
+        .or(local_ips.first())
+        .copied();
 
     // Get public IP
-    match get_public_ip().await {
+    let public_ip = match get_public_ip().await {
         Ok(public_ip) => {
             info!("🌍 Public IP address: {}", public_ip);
             info!("");
@@ -525,6 +459,9 @@ async fn display_network_info(sites: &[SiteConfig]) {
                 for site in sites {
                     let protocol = if site.https { "https" } else { "http" };
                     info!("   📁 {} (port {}):", site.name, site.port);
+                    for hostname in &site.hostnames {
+                        info!("      🏷️  Host: {}://{}:{}", protocol, hostname, site.port);
+                    }
                     info!("      📱 Local: {}://localhost:{}", protocol, site.port);
                     if let Some(local) = &local_ip {
                         info!("      📱 Network: {}://{}:{}", protocol, local, site.port);
@@ -539,11 +476,13 @@ async fn display_network_info(sites: &[SiteConfig]) {
             
             info!("");
             info!("🛠️  Network Setup:");
+            let ports: std::collections::BTreeSet<u16> = sites.iter().map(|s| s.port).collect();
             info!("   1. Port forwarding: Configure router for ports: {}", 
-                sites.iter().map(|s| s.port.to_string()).collect::<Vec<_>>().join(", "));
+                ports.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", "));
             info!("   2. Windows Firewall: Run setup-firewall.ps1 as Administrator");
             info!("   3. DNS Setup: Create A record → {}", public_ip);
             
+            Some(public_ip)
         }
         Err(e) => {
             warn!("Failed to detect public IP: {}", e);
@@ -559,11 +498,17 @@ async fn display_network_info(sites: &[SiteConfig]) {
                 info!("📱 Multi-Site Local Access:");
                 for site in sites {
                     let protocol = if site.https { "https" } else { "http" };
-                    info!("   {} → {}://localhost:{}", site.name, protocol, site.port);
+                    match site.hostnames.first() {
+                        Some(hostname) => info!("   {} → {}://{}:{}", site.name, protocol, hostname, site.port),
+                        None => info!("   {} → {}://localhost:{}", site.name, protocol, site.port),
+                    }
                 }
             }
             
             info!("💡 Find your public IP at: https://whatismyipaddress.com");
+            None
         }
-    }
-}
\ No newline at end of file
+    };
+
+    NetworkInfo { local_ip, local_ips, public_ip }
+}
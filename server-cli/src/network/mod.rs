@@ -1,2 +1,12 @@
 pub mod ip_detection;
-pub use ip_detection::{get_public_ip, get_local_ips};
+pub use ip_detection::{get_public_ip, get_local_ips, is_private_ip};
+
+use std::net::IpAddr;
+
+/// Addresses detected at startup, used to build the URLs sites are reachable at.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkInfo {
+    pub local_ip: Option<IpAddr>,
+    pub local_ips: Vec<IpAddr>,
+    pub public_ip: Option<String>,
+}
@@ -0,0 +1,217 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::json;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use super::manager::{ManagerError, SiteManager};
+use crate::config::{validate_directory, ConfigSite, SiteConfig};
+
+#[derive(Clone)]
+struct AdminState {
+    manager: Arc<SiteManager>,
+    token: Arc<str>,
+}
+
+/// Serve the admin API on an already bound listener until `shutdown` is
+/// cancelled. Every request must carry `Authorization: Bearer <token>`.
+///
+/// Endpoints:
+/// - `GET /sites` — list sites with their status and URLs
+/// - `POST /sites` — add a site (same fields as a `[[sites]]` config entry)
+/// - `GET /sites/:name` — show one site
+/// - `DELETE /sites/:name` — stop and remove a site
+/// - `POST /sites/:name/start` and `POST /sites/:name/stop`
+pub async fn serve_admin(
+    listener: TcpListener,
+    token: String,
+    manager: Arc<SiteManager>,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("🛠️  Admin API listening on http://{}", listener.local_addr()?);
+
+    axum::serve(listener, admin_router(manager, token))
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+fn admin_router(manager: Arc<SiteManager>, token: String) -> Router {
+    let state = AdminState {
+        manager,
+        token: token.into(),
+    };
+
+    Router::new()
+        .route("/sites", get(list_sites).post(add_site))
+        .route("/sites/:name", get(show_site).delete(remove_site))
+        .route("/sites/:name/start", post(start_site))
+        .route("/sites/:name/stop", post(stop_site))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+async fn require_token(State(state): State<AdminState>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // Constant time, so response timing doesn't give the token away
+        .is_some_and(|token| bool::from(token.as_bytes().ct_eq(state.token.as_bytes())));
+
+    if !authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+    next.run(req).await
+}
+
+async fn list_sites(State(state): State<AdminState>) -> Response {
+    Json(json!({ "sites": state.manager.sites().await })).into_response()
+}
+
+async fn show_site(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    match state.manager.site(&name).await {
+        Ok(site) => Json(site).into_response(),
+        Err(e) => manager_error(e),
+    }
+}
+
+async fn add_site(State(state): State<AdminState>, Json(site): Json<ConfigSite>) -> Response {
+    let site: SiteConfig = site.into();
+    // Validation reads the site's files, certificates among them
+    let checked = tokio::task::spawn_blocking(move || {
+        validate_directory(&site.root).map_err(|e| e.to_string())?;
+        site.validate()?;
+        Ok::<_, String>(site)
+    })
+    .await;
+    let site = match checked {
+        Ok(Ok(site)) => site,
+        Ok(Err(e)) => return error_response(StatusCode::BAD_REQUEST, &e),
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+
+    let name = site.name.clone();
+    if let Err(e) = state.manager.add_site(site).await {
+        return manager_error(e);
+    }
+    info!("➕ Added site {} via admin API", name);
+    site_response(&state, &name, StatusCode::CREATED).await
+}
+
+async fn remove_site(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    match state.manager.remove_site(&name).await {
+        Ok(()) => {
+            info!("➖ Removed site {} via admin API", name);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => manager_error(e),
+    }
+}
+
+async fn start_site(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    if let Err(e) = state.manager.start_site(&name).await {
+        return manager_error(e);
+    }
+    info!("▶️  Started site {} via admin API", name);
+    site_response(&state, &name, StatusCode::OK).await
+}
+
+async fn stop_site(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    if let Err(e) = state.manager.stop_site(&name).await {
+        return manager_error(e);
+    }
+    info!("⏹️  Stopped site {} via admin API", name);
+    site_response(&state, &name, StatusCode::OK).await
+}
+
+async fn site_response(state: &AdminState, name: &str, status: StatusCode) -> Response {
+    match state.manager.site(name).await {
+        Ok(site) => (status, Json(site)).into_response(),
+        Err(e) => manager_error(e),
+    }
+}
+
+fn manager_error(e: ManagerError) -> Response {
+    let status = match e {
+        ManagerError::NotFound(_) => StatusCode::NOT_FOUND,
+        ManagerError::AlreadyExists(_) => StatusCode::CONFLICT,
+        ManagerError::InvalidConfig(_) => StatusCode::BAD_REQUEST,
+    };
+    error_response(status, &e.to_string())
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkInfo;
+    use axum::body::Body;
+    use std::time::Duration;
+    use tower::util::ServiceExt;
+
+    async fn call(router: &Router, method: &str, uri: &str, token: Option<&str>, body: Option<String>) -> (StatusCode, serde_json::Value) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        if body.is_some() {
+            req = req.header(header::CONTENT_TYPE, "application/json");
+        }
+        let req = req.body(body.map(Body::from).unwrap_or_else(Body::empty)).unwrap();
+        let response = router.clone().oneshot(req).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let manager = SiteManager::new("127.0.0.1", NetworkInfo::default(), Duration::from_secs(1));
+        let router = admin_router(manager, "secret".to_string());
+
+        assert_eq!(call(&router, "GET", "/sites", None, None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&router, "GET", "/sites", Some("wrong"), None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&router, "GET", "/sites", Some("secret "), None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&router, "GET", "/sites", Some("secre"), None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&router, "GET", "/sites", Some("secret"), None).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_site_lifecycle() {
+        let manager = SiteManager::new("127.0.0.1", NetworkInfo::default(), Duration::from_secs(1));
+        let router = admin_router(manager, "secret".to_string());
+        let token = Some("secret");
+
+        let site = json!({ "name": "docs", "root": ".", "port": 0 }).to_string();
+        let (status, body) = call(&router, "POST", "/sites", token, Some(site.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["status"], "running");
+        assert!(body["urls"]["local"].as_str().unwrap().starts_with("http://localhost:"));
+
+        assert_eq!(call(&router, "POST", "/sites", token, Some(site)).await.0, StatusCode::CONFLICT);
+
+        let (status, body) = call(&router, "POST", "/sites/docs/stop", token, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "stopped");
+
+        let (_, body) = call(&router, "GET", "/sites", token, None).await;
+        assert_eq!(body["sites"].as_array().unwrap().len(), 1);
+
+        assert_eq!(call(&router, "DELETE", "/sites/docs", token, None).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(call(&router, "GET", "/sites/docs", token, None).await.0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,296 @@
+//! Browser caching of static files from `[sites.cache]`: `Cache-Control` by
+//! glob or extension, ETags with 304 answers to `If-None-Match`, and a dev
+//! mode that turns caching off. Only responses marked with `mark_static`
+//! are touched, proxied responses keep the backend's caching headers.
+//!
+//! ETags are built from the file's modification time and size, like nginx
+//! does, with the encoding appended for compressed responses. The policy
+//! runs outside the compression layer so responses compressed on the fly
+//! get a tag of their own too.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use globset::{GlobBuilder, GlobMatcher};
+use std::{sync::Arc, time::UNIX_EPOCH};
+
+use crate::config::{CacheConfig, CacheRuleConfig};
+
+/// Marks a response as a static file; `path` is what cache rules match.
+#[derive(Debug, Clone)]
+pub struct StaticFile {
+    path: String,
+    /// Modification time and size of the file as served, before any
+    /// compression on the fly
+    version: Option<(u64, u64)>,
+}
+
+/// The compiled `[sites.cache]` of a site.
+#[derive(Debug)]
+pub struct CachePolicy {
+    dev: bool,
+    etag: bool,
+    rules: Vec<CacheRule>,
+}
+
+#[derive(Debug)]
+struct CacheRule {
+    matcher: CacheMatcher,
+    cache_control: HeaderValue,
+}
+
+#[derive(Debug)]
+enum CacheMatcher {
+    Glob(GlobMatcher),
+    Extensions(Vec<String>),
+}
+
+impl CachePolicy {
+    pub fn compile(config: &CacheConfig) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(index, rule)| CacheRule::compile(rule).map_err(|e| format!("Cache rule {}: {}", index + 1, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            dev: config.dev.unwrap_or(false),
+            etag: config.etag.unwrap_or(true),
+            rules,
+        })
+    }
+
+    /// Set the caching headers of a static file response, turning it into
+    /// a 304 when `if_none_match` lists its ETag.
+    pub fn apply(&self, if_none_match: Option<&HeaderValue>, mut response: Response) -> Response {
+        let Some(file) = response.extensions().get::<StaticFile>().cloned() else {
+            return response;
+        };
+        let status = response.status();
+        let headers = response.headers_mut();
+
+        if self.dev {
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            headers.remove(header::ETAG);
+            headers.remove(header::LAST_MODIFIED);
+            return response;
+        }
+
+        // Rules are for the files, a missing file must not be cached as one
+        let cacheable = status.is_success() || status == StatusCode::NOT_MODIFIED;
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(&file.path)).filter(|_| cacheable) {
+            headers.insert(header::CACHE_CONTROL, rule.cache_control.clone());
+        }
+
+        if !self.etag || !(status == StatusCode::OK || status == StatusCode::PARTIAL_CONTENT) {
+            return response;
+        }
+        let Some(etag) = file.version.and_then(|version| file_etag(version, response.headers())) else {
+            return response;
+        };
+        response.headers_mut().insert(header::ETAG, etag.clone());
+
+        match if_none_match {
+            Some(if_none_match) if etag_matches(if_none_match, &etag) => not_modified(response.headers()),
+            _ => response,
+        }
+    }
+}
+
+impl CacheRule {
+    fn compile(rule: &CacheRuleConfig) -> Result<Self, String> {
+        let matcher = match (&rule.glob, &rule.extensions) {
+            (Some(glob), None) => {
+                let glob = GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| format!("invalid glob {}: {}", glob, e))?;
+                CacheMatcher::Glob(glob.compile_matcher())
+            }
+            (None, Some(extensions)) if !extensions.is_empty() => CacheMatcher::Extensions(
+                extensions
+                    .iter()
+                    .map(|extension| extension.trim().trim_start_matches('.').to_ascii_lowercase())
+                    .collect(),
+            ),
+            _ => return Err("needs exactly one of glob or extensions".to_string()),
+        };
+        let cache_control = HeaderValue::try_from(rule.cache_control.as_str())
+            .map_err(|_| format!("invalid cache_control {}", rule.cache_control))?;
+        Ok(Self { matcher, cache_control })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match &self.matcher {
+            CacheMatcher::Glob(glob) => glob.is_match(path),
+            CacheMatcher::Extensions(extensions) => {
+                let file_name = path.rsplit('/').next().unwrap_or_default();
+                file_name
+                    .rsplit_once('.')
+                    .is_some_and(|(_, extension)| extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+            }
+        }
+    }
+}
+
+/// Mark `response` as the static file at `path` for the cache policy.
+/// Directories are matched as the `index.html` they are served from.
+pub fn mark_static(mut response: Response, path: &str) -> Response {
+    let path = if path.ends_with('/') { format!("{}index.html", path) } else { path.to_string() };
+    let version = file_version(response.headers());
+    response.extensions_mut().insert(StaticFile { path, version });
+    response
+}
+
+/// Leave conditional requests to `If-None-Match` when a client sends it, as
+/// RFC 9110 asks; `ServeDir` would otherwise answer 304 by date alone.
+pub fn prefer_etag(req: &mut Request) {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+}
+
+/// Modification time and size of a file response, as `ServeDir` sends it.
+fn file_version(headers: &HeaderMap) -> Option<(u64, u64)> {
+    let modified = httpdate::parse_http_date(headers.get(header::LAST_MODIFIED)?.to_str().ok()?).ok()?;
+    let modified = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    // Range responses carry the size of the whole file after the slash
+    let size: u64 = match headers.get(header::CONTENT_RANGE) {
+        Some(range) => range.to_str().ok()?.rsplit('/').next()?.parse().ok()?,
+        None => headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()?,
+    };
+    Some((modified, size))
+}
+
+/// The ETag of a file version in the encoding the response ends up with.
+fn file_etag((modified, size): (u64, u64), headers: &HeaderMap) -> Option<HeaderValue> {
+    let etag = match headers.get(header::CONTENT_ENCODING).and_then(|value| value.to_str().ok()) {
+        Some(encoding) => format!("\"{:x}-{:x}-{}\"", modified, size, encoding),
+        None => format!("\"{:x}-{:x}\"", modified, size),
+    };
+    HeaderValue::try_from(etag).ok()
+}
+
+/// `If-None-Match` uses the weak comparison, so `W/` prefixes are ignored.
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (Ok(tags), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// A 304 keeping the headers a cache needs to update its stored copy.
+fn not_modified(headers: &HeaderMap) -> Response {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in [header::CACHE_CONTROL, header::ETAG, header::LAST_MODIFIED, header::VARY, header::EXPIRES] {
+        if let Some(value) = headers.get(&name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+    response
+}
+
+/// Middleware applying a site's cache policy to static file responses.
+pub async fn apply_cache_policy(State(policy): State<Arc<CachePolicy>>, req: Request, next: Next) -> Response {
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+    let response = next.run(req).await;
+    policy.apply(if_none_match.as_ref(), response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_response(path: &str) -> Response {
+        let mut response = Response::new(Body::from("body"));
+        let headers = response.headers_mut();
+        headers.insert(header::LAST_MODIFIED, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("4"));
+        mark_static(response, path)
+    }
+
+    fn rule(glob: Option<&str>, extensions: Option<&[&str]>, cache_control: &str) -> CacheRuleConfig {
+        CacheRuleConfig {
+            glob: glob.map(str::to_string),
+            extensions: extensions.map(|extensions| extensions.iter().map(|e| e.to_string()).collect()),
+            cache_control: cache_control.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rules_and_etags() {
+        let config = CacheConfig {
+            rules: Some(vec![
+                rule(Some("/assets/**"), None, "public, max-age=31536000, immutable"),
+                rule(None, Some(&[".html"]), "no-cache"),
+            ]),
+            ..Default::default()
+        };
+        let policy = CachePolicy::compile(&config).unwrap();
+
+        let response = policy.apply(None, file_response("/assets/app.3f2a.js"));
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=31536000, immutable");
+        let etag = response.headers()[header::ETAG].clone();
+        assert_eq!(etag, "\"56273e80-4\"");
+
+        for path in ["/docs/index.HTML", "/docs/"] {
+            let response = policy.apply(None, file_response(path));
+            assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache", "{}", path);
+        }
+        assert!(policy.apply(None, file_response("/logo.png")).headers().get(header::CACHE_CONTROL).is_none());
+        let mut missing = file_response("/assets/gone.js");
+        *missing.status_mut() = StatusCode::NOT_FOUND;
+        assert!(policy.apply(None, missing).headers().get(header::CACHE_CONTROL).is_none());
+
+        let revalidated = policy.apply(Some(&HeaderValue::from_static("\"old\", W/\"56273e80-4\"")), file_response("/index.html"));
+        assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(revalidated.headers()[header::ETAG], etag);
+        assert_eq!(revalidated.headers()[header::CACHE_CONTROL], "no-cache");
+
+        let changed = policy.apply(Some(&HeaderValue::from_static("\"old\"")), file_response("/index.html"));
+        assert_eq!(changed.status(), StatusCode::OK);
+
+        // Responses that aren't static files are left alone
+        let proxied = policy.apply(Some(&HeaderValue::from_static("*")), Response::new(Body::empty()));
+        assert_eq!(proxied.status(), StatusCode::OK);
+        assert!(proxied.headers().is_empty());
+    }
+
+    #[test]
+    fn test_dev_mode_turns_caching_off() {
+        let config = CacheConfig {
+            dev: Some(true),
+            rules: Some(vec![rule(Some("/**"), None, "max-age=600")]),
+            ..Default::default()
+        };
+        let policy = CachePolicy::compile(&config).unwrap();
+        let response = policy.apply(Some(&HeaderValue::from_static("*")), file_response("/app.js"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        assert!(response.headers().get(header::ETAG).is_none());
+        assert!(response.headers().get(header::LAST_MODIFIED).is_none());
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        let invalid = [
+            rule(None, None, "no-cache"),
+            rule(Some("/a"), Some(&["js"]), "no-cache"),
+            rule(None, Some(&[]), "no-cache"),
+            rule(Some("/assets/[a"), None, "no-cache"),
+            rule(Some("/a"), None, "no\ncache"),
+        ];
+        for rule in invalid {
+            let config = CacheConfig { rules: Some(vec![rule]), ..Default::default() };
+            assert!(CachePolicy::compile(&config).is_err());
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Per-site response compression from `[sites.compression]`. Static files
+//! with a `.br`, `.gz` or `.zst` sibling are served precompressed; anything
+//! else, proxied responses included, is compressed on the fly when the
+//! client accepts it and the response passes the size and type filters.
+
+use axum::{
+    body::HttpBody,
+    http::{header, Response},
+};
+use std::sync::Arc;
+use tower_http::{
+    compression::{predicate::Predicate, CompressionLayer},
+    services::ServeDir,
+};
+
+use crate::config::CompressionConfig;
+
+/// Responses smaller than this aren't worth compressing
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+/// Content types compressed when a site doesn't list its own
+const DEFAULT_MIME_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/manifest+json",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// Which responses are compressed on the fly.
+#[derive(Debug, Clone)]
+pub struct CompressFilter {
+    min_size: u64,
+    mime_types: Arc<[MimeType]>,
+}
+
+#[derive(Debug)]
+enum MimeType {
+    /// `text/*`, stored as `text/`
+    Any(String),
+    Exact(String),
+}
+
+impl MimeType {
+    fn parse(mime_type: &str) -> Result<Self, String> {
+        let mime_type = mime_type.trim().to_ascii_lowercase();
+        match mime_type.split_once('/') {
+            Some((kind, _)) if kind.is_empty() || kind.contains('*') => {
+                Err(format!("Invalid MIME type in compression.mime_types: {}", mime_type))
+            }
+            Some((kind, "*")) => Ok(Self::Any(format!("{}/", kind))),
+            Some((_, subtype)) if !subtype.is_empty() && !subtype.contains('*') => Ok(Self::Exact(mime_type)),
+            _ => Err(format!("Invalid MIME type in compression.mime_types: {}", mime_type)),
+        }
+    }
+
+    fn matches(&self, essence: &str) -> bool {
+        match self {
+            Self::Any(prefix) => essence.starts_with(prefix.as_str()),
+            Self::Exact(mime_type) => essence == mime_type,
+        }
+    }
+}
+
+impl Predicate for CompressFilter {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        let headers = response.headers();
+        let essence = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        // Event streams have to reach the browser event by event
+        if essence == "text/event-stream" || !self.mime_types.iter().any(|mime_type| mime_type.matches(&essence)) {
+            return false;
+        }
+
+        // Bodies of unknown length are streams that compress well enough
+        let size = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .or_else(|| response.body().size_hint().exact());
+        size.is_none_or(|size| size >= self.min_size)
+    }
+}
+
+/// The compression layer for a site, or `None` when compression is off.
+pub fn compression_layer(config: &CompressionConfig) -> Result<Option<CompressionLayer<CompressFilter>>, String> {
+    if config.enabled == Some(false) {
+        return Ok(None);
+    }
+    let (gzip, br, zstd) = algorithms(config);
+    if !(gzip || br || zstd) {
+        return Err("compression has gzip, br and zstd all off, set enabled = false instead".to_string());
+    }
+
+    let mime_types: Result<Arc<[MimeType]>, String> = match &config.mime_types {
+        Some(mime_types) if mime_types.is_empty() => {
+            return Err("compression.mime_types is empty, set enabled = false instead".to_string())
+        }
+        Some(mime_types) => mime_types.iter().map(|mime_type| MimeType::parse(mime_type)).collect(),
+        None => DEFAULT_MIME_TYPES.iter().map(|mime_type| MimeType::parse(mime_type)).collect(),
+    };
+    let filter = CompressFilter {
+        min_size: config.min_size.unwrap_or(DEFAULT_MIN_SIZE),
+        mime_types: mime_types?,
+    };
+
+    let layer = CompressionLayer::new()
+        .gzip(gzip)
+        .br(br)
+        .zstd(zstd)
+        .no_deflate()
+        .compress_when(filter);
+    Ok(Some(layer))
+}
+
+/// Let `serve_dir` answer with precompressed siblings of the requested file.
+pub fn serve_precompressed(mut serve_dir: ServeDir, config: &CompressionConfig) -> ServeDir {
+    if config.enabled == Some(false) || config.precompressed == Some(false) {
+        return serve_dir;
+    }
+    let (gzip, br, zstd) = algorithms(config);
+    if gzip {
+        serve_dir = serve_dir.precompressed_gzip();
+    }
+    if br {
+        serve_dir = serve_dir.precompressed_br();
+    }
+    if zstd {
+        serve_dir = serve_dir.precompressed_zstd();
+    }
+    serve_dir
+}
+
+fn algorithms(config: &CompressionConfig) -> (bool, bool, bool) {
+    (
+        config.gzip.unwrap_or(true),
+        config.br.unwrap_or(true),
+        config.zstd.unwrap_or(true),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::TempSite;
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::util::ServiceExt;
+
+    async fn fetch(app: &Router, path: &str, accept_encoding: &str) -> Response<Body> {
+        let req = Request::builder()
+            .uri(path)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap()
+    }
+
+    fn encoding<B>(response: &Response<B>) -> Option<&str> {
+        response.headers().get(header::CONTENT_ENCODING).map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_compression_filters() {
+        let config = CompressionConfig { mime_types: Some(vec!["text/*".to_string()]), ..Default::default() };
+        let text = "hello ".repeat(500);
+        let app = Router::new()
+            .route("/big", get(move || async move { text }))
+            .route("/small", get(|| async { "hello" }))
+            .route("/image", get(|| async { ([(header::CONTENT_TYPE, "image/png")], vec![0u8; 4096]) }))
+            .layer(compression_layer(&config).unwrap().unwrap());
+
+        assert_eq!(encoding(&fetch(&app, "/big", "gzip, br").await), Some("br"));
+        assert_eq!(encoding(&fetch(&app, "/big", "zstd").await), Some("zstd"));
+        assert_eq!(encoding(&fetch(&app, "/big", "identity").await), None);
+        assert_eq!(encoding(&fetch(&app, "/small", "gzip").await), None);
+        assert_eq!(encoding(&fetch(&app, "/image", "gzip").await), None);
+
+        let no_br = CompressionConfig { br: Some(false), ..config };
+        let app = Router::new()
+            .route("/big", get(|| async { "hello ".repeat(500) }))
+            .layer(compression_layer(&no_br).unwrap().unwrap());
+        assert_eq!(encoding(&fetch(&app, "/big", "br, gzip").await), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn test_precompressed_siblings_are_preferred() {
+        let site = TempSite::new();
+        site.write("app.js", "boot()".repeat(500));
+        site.write("app.js.br", "prebuilt brotli");
+
+        let config = CompressionConfig::default();
+        let app = Router::new()
+            .fallback_service(serve_precompressed(ServeDir::new(&site.root), &config))
+            .layer(compression_layer(&config).unwrap().unwrap());
+
+        let response = fetch(&app, "/app.js", "br").await;
+        assert_eq!(encoding(&response), Some("br"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"prebuilt brotli");
+
+        // Without a .gz sibling gzip is done on the fly
+        assert_eq!(encoding(&fetch(&app, "/app.js", "gzip").await), Some("gzip"));
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let disabled = CompressionConfig { enabled: Some(false), ..Default::default() };
+        assert!(compression_layer(&disabled).unwrap().is_none());
+
+        let invalid = [
+            CompressionConfig { gzip: Some(false), br: Some(false), zstd: Some(false), ..Default::default() },
+            CompressionConfig { mime_types: Some(Vec::new()), ..Default::default() },
+            CompressionConfig { mime_types: Some(vec!["json".to_string()]), ..Default::default() },
+            CompressionConfig { mime_types: Some(vec!["*/json".to_string()]), ..Default::default() },
+        ];
+        for config in invalid {
+            assert!(compression_layer(&config).is_err(), "{:?}", config);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Per-site CORS from `[sites.cors]`. Sites without the section let any
+//! origin call them, which is what most local development wants; listing
+//! origins is needed for credentialed requests, and `passthrough` leaves
+//! CORS to a backend that handles it itself.
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
+
+use crate::config::CorsConfig;
+
+/// The CORS layer for a site, or `None` when CORS is passed through to the
+/// backend.
+pub fn cors_layer(config: Option<&CorsConfig>) -> Result<Option<CorsLayer>, String> {
+    let Some(config) = config else {
+        return Ok(Some(CorsLayer::permissive()));
+    };
+    if config.passthrough == Some(true) {
+        let configured = config.allow_origins.is_some()
+            || config.allow_methods.is_some()
+            || config.allow_headers.is_some()
+            || config.expose_headers.is_some()
+            || config.allow_credentials.is_some()
+            || config.max_age.is_some();
+        if configured {
+            return Err("cors.passthrough can't be combined with other cors settings".to_string());
+        }
+        return Ok(None);
+    }
+
+    // Browsers reject `*` on credentialed requests, so "any" methods and
+    // headers are sent back as the ones the request asked for instead
+    let credentials = config.allow_credentials == Some(true);
+
+    let origins = match wildcard_or_list(&config.allow_origins, "allow_origins")? {
+        None => {
+            if credentials {
+                return Err("cors.allow_credentials needs the origins listed in cors.allow_origins".to_string());
+            }
+            AllowOrigin::any()
+        }
+        Some(origins) => AllowOrigin::list(origins.iter().map(|origin| origin_value(origin)).collect::<Result<Vec<_>, _>>()?),
+    };
+
+    let methods = match wildcard_or_list(&config.allow_methods, "allow_methods")? {
+        None if credentials => AllowMethods::mirror_request(),
+        None => AllowMethods::any(),
+        Some(methods) => AllowMethods::list(
+            methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.trim().to_uppercase().as_bytes())
+                        .map_err(|_| format!("Invalid method in cors.allow_methods: {}", method))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+
+    let headers = match wildcard_or_list(&config.allow_headers, "allow_headers")? {
+        None if credentials => AllowHeaders::mirror_request(),
+        None => AllowHeaders::any(),
+        Some(headers) => AllowHeaders::list(header_names(headers, "allow_headers")?),
+    };
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(credentials);
+    if config.expose_headers.is_some() {
+        layer = layer.expose_headers(match wildcard_or_list(&config.expose_headers, "expose_headers")? {
+            None if credentials => {
+                return Err("cors.expose_headers can't be * with cors.allow_credentials, list the headers".to_string())
+            }
+            None => ExposeHeaders::any(),
+            Some(headers) => ExposeHeaders::list(header_names(headers, "expose_headers")?),
+        });
+    }
+    if let Some(seconds) = config.max_age {
+        layer = layer.max_age(Duration::from_secs(seconds));
+    }
+    Ok(Some(layer))
+}
+
+/// `None` for an unset list or `*`, otherwise the listed values.
+fn wildcard_or_list<'a>(list: &'a Option<Vec<String>>, field: &str) -> Result<Option<&'a [String]>, String> {
+    match list.as_deref() {
+        None => Ok(None),
+        Some([only]) if only.trim() == "*" => Ok(None),
+        Some(values) if values.iter().any(|value| value.trim() == "*") => {
+            Err(format!("cors.{} can't mix * with other values", field))
+        }
+        Some([]) => Err(format!("cors.{} is empty, leave it out to allow any", field)),
+        Some(values) => Ok(Some(values)),
+    }
+}
+
+fn origin_value(origin: &str) -> Result<HeaderValue, String> {
+    let trimmed = origin.trim();
+    // Browsers send the origin as scheme://host[:port] with nothing after it,
+    // so `http://localhost:3000/` would never match
+    let valid = match trimmed.split_once("://") {
+        Some((scheme, authority)) => !scheme.is_empty() && !authority.is_empty() && !authority.contains('/'),
+        None => trimmed == "null",
+    };
+    if !valid {
+        return Err(format!("Invalid origin in cors.allow_origins, expected scheme://host[:port]: {}", origin));
+    }
+    HeaderValue::try_from(trimmed).map_err(|_| format!("Invalid origin in cors.allow_origins: {}", origin))
+}
+
+fn header_names(names: &[String], field: &str) -> Result<Vec<HeaderName>, String> {
+    names
+        .iter()
+        .map(|name| HeaderName::try_from(name.trim()).map_err(|_| format!("Invalid header name in cors.{}: {}", field, name)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        routing::get,
+        Router,
+    };
+    use tower::util::ServiceExt;
+
+    fn strings(values: &[&str]) -> Option<Vec<String>> {
+        Some(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    async fn preflight(config: CorsConfig, origin: &str) -> axum::http::HeaderMap {
+        let layer = cors_layer(Some(&config)).unwrap().unwrap();
+        let app = Router::new().route("/api", get(|| async { "ok" })).layer(layer);
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-token")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap().headers().clone()
+    }
+
+    #[tokio::test]
+    async fn test_listed_origins_with_credentials() {
+        let config = CorsConfig {
+            allow_origins: strings(&["http://localhost:5173"]),
+            allow_credentials: Some(true),
+            max_age: Some(600),
+            ..Default::default()
+        };
+
+        let headers = preflight(config.clone(), "http://localhost:5173").await;
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:5173");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "PUT");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let headers = preflight(config, "http://evil.example").await;
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn test_passthrough_and_invalid_settings() {
+        assert!(cors_layer(None).unwrap().is_some());
+        let passthrough = CorsConfig { passthrough: Some(true), ..Default::default() };
+        assert!(cors_layer(Some(&passthrough)).unwrap().is_none());
+
+        let invalid = [
+            CorsConfig { passthrough: Some(true), max_age: Some(60), ..Default::default() },
+            CorsConfig { allow_credentials: Some(true), ..Default::default() },
+            CorsConfig { allow_origins: strings(&["*"]), allow_credentials: Some(true), ..Default::default() },
+            CorsConfig {
+                allow_origins: strings(&["http://app.local"]),
+                expose_headers: strings(&["*"]),
+                allow_credentials: Some(true),
+                ..Default::default()
+            },
+            CorsConfig { allow_origins: strings(&["http://localhost:3000/"]), ..Default::default() },
+            CorsConfig { allow_origins: strings(&["*", "http://app.local"]), ..Default::default() },
+            CorsConfig { allow_methods: strings(&["GE T"]), ..Default::default() },
+            CorsConfig { allow_headers: strings(&["x bad"]), ..Default::default() },
+        ];
+        for config in invalid {
+            assert!(cors_layer(Some(&config)).is_err(), "{:?}", config);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Per-site error pages from `error_pages`. Only errors the server makes
+//! itself are replaced: missing static files and failed proxy requests.
+//! Error responses from a backend are passed on untouched. Browsers get the
+//! configured HTML page, API clients asking for JSON get a JSON error.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+use tracing::warn;
+
+use super::cache::StaticFile;
+
+/// Marks an error response made by the server rather than a backend.
+#[derive(Debug, Clone, Copy)]
+pub struct GeneratedError;
+
+/// Mark `response` as an error made by the server.
+pub fn mark_generated(mut response: Response) -> Response {
+    response.extensions_mut().insert(GeneratedError);
+    response
+}
+
+/// The error pages of a site, as files under its root.
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    pages: BTreeMap<u16, PathBuf>,
+}
+
+/// What kind of error body the client asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Wants {
+    Html,
+    Json,
+    Other,
+}
+
+impl Wants {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        if accept.contains("text/html") {
+            Wants::Html
+        } else if accept.contains("application/json") || accept.contains("+json") {
+            Wants::Json
+        } else {
+            Wants::Other
+        }
+    }
+}
+
+impl ErrorPages {
+    pub fn new(pages: &BTreeMap<u16, PathBuf>) -> Self {
+        for file in pages.values() {
+            if !file.is_file() {
+                warn!("⚠️  Error page {} does not exist yet", file.display());
+            }
+        }
+        Self { pages: pages.clone() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    async fn apply(&self, wants: Wants, response: Response) -> Response {
+        let status = response.status();
+        let generated = response.extensions().get::<GeneratedError>().is_some()
+            || response.extensions().get::<StaticFile>().is_some();
+        if !generated || !(status.is_client_error() || status.is_server_error()) {
+            return response;
+        }
+
+        match wants {
+            Wants::Html => match self.pages.get(&status.as_u16()) {
+                Some(file) => match tokio::fs::read(file).await {
+                    Ok(page) => replace_body(response, "text/html; charset=utf-8", page),
+                    Err(e) => {
+                        warn!("❌ Can't read error page {}: {}", file.display(), e);
+                        response
+                    }
+                },
+                None => response,
+            },
+            Wants::Json if !is_json(response.headers()) => {
+                let reason = status.canonical_reason().unwrap_or("Error");
+                let body = serde_json::json!({ "error": reason, "status": status.as_u16() }).to_string();
+                replace_body(response, "application/json", body.into_bytes())
+            }
+            _ => response,
+        }
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+/// Swap the body, keeping the status, extensions and headers such as `Allow`
+/// that don't describe the old body.
+fn replace_body(response: Response, content_type: &'static str, body: Vec<u8>) -> Response {
+    let (mut parts, _) = response.into_parts();
+    for name in [
+        header::CONTENT_LENGTH,
+        header::CONTENT_ENCODING,
+        header::CONTENT_RANGE,
+        header::ETAG,
+        header::LAST_MODIFIED,
+    ] {
+        parts.headers.remove(name);
+    }
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Middleware replacing the server's error responses with the site's pages.
+pub async fn apply_error_pages(State(pages): State<Arc<ErrorPages>>, req: Request, next: Next) -> Response {
+    let wants = Wants::from_headers(req.headers());
+    let response = next.run(req).await;
+    pages.apply(wants, response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{cache::mark_static, testing::TempSite};
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    async fn body(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pages_replace_generated_errors_only() {
+        let site = TempSite::new();
+        let lost = site.write("404.html", "<h1>Lost</h1>");
+        let pages = ErrorPages::new(&BTreeMap::from([(404, lost), (502, site.root.join("missing.html"))]));
+
+        let not_found = || mark_static(StatusCode::NOT_FOUND.into_response(), "/nope");
+        let response = pages.apply(Wants::Html, not_found()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(body(response).await, "<h1>Lost</h1>");
+
+        let response = pages.apply(Wants::Json, not_found()).await;
+        assert_eq!(body(response).await, r#"{"error":"Not Found","status":404}"#);
+
+        // A backend's own 404 is its business
+        let response = pages.apply(Wants::Html, (StatusCode::NOT_FOUND, "backend page").into_response()).await;
+        assert_eq!(body(response).await, "backend page");
+
+        // A page that can't be read leaves the original error
+        let bad_gateway = mark_generated((StatusCode::BAD_GATEWAY, "down").into_response());
+        assert_eq!(body(pages.apply(Wants::Html, bad_gateway).await).await, "down");
+    }
+}
@@ -0,0 +1,131 @@
+//! Per-site response header policy from `[sites.headers]`: security headers
+//! such as HSTS and CSP, custom headers, and headers to strip. It wraps the
+//! whole site router, so static files, proxied responses and redirects all
+//! get the same headers.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+
+use crate::config::HeadersConfig;
+
+/// The compiled `[sites.headers]` of a site.
+#[derive(Debug, Default)]
+pub struct HeaderPolicy {
+    set: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl HeaderPolicy {
+    pub fn compile(config: &HeadersConfig, https: bool) -> Result<Self, String> {
+        if config.hsts.is_some() && !https {
+            return Err("headers.hsts needs https, browsers ignore it over plain HTTP".to_string());
+        }
+
+        let named = [
+            (header::STRICT_TRANSPORT_SECURITY, &config.hsts),
+            (header::CONTENT_SECURITY_POLICY, &config.content_security_policy),
+            (header::X_FRAME_OPTIONS, &config.x_frame_options),
+            (header::REFERRER_POLICY, &config.referrer_policy),
+            (HeaderName::from_static("permissions-policy"), &config.permissions_policy),
+        ];
+        let mut set = Vec::new();
+        for (name, value) in named {
+            if let Some(value) = value {
+                set.push((name.clone(), header_value(&name, value)?));
+            }
+        }
+        for (name, value) in config.custom.iter().flatten() {
+            let name = header_name(name)?;
+            set.push((name.clone(), header_value(&name, value)?));
+        }
+
+        let remove = config
+            .remove
+            .iter()
+            .flatten()
+            .map(|name| header_name(name))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { set, remove })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.remove.is_empty()
+    }
+
+    /// Remove the listed headers, then set the configured ones, replacing
+    /// any with the same name.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::try_from(name.trim()).map_err(|_| format!("Invalid header name in headers: {}", name))
+}
+
+fn header_value(name: &HeaderName, value: &str) -> Result<HeaderValue, String> {
+    HeaderValue::try_from(value).map_err(|_| format!("Invalid value for header {}: {}", name, value))
+}
+
+/// Middleware applying a site's header policy to every response.
+pub async fn apply_header_policy(State(policy): State<Arc<HeaderPolicy>>, req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    policy.apply(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_policy_sets_and_removes_headers() {
+        let config = HeadersConfig {
+            hsts: Some("max-age=31536000".to_string()),
+            x_frame_options: Some("DENY".to_string()),
+            custom: Some(BTreeMap::from([("X-Env".to_string(), "dev".to_string())])),
+            remove: Some(vec!["X-Powered-By".to_string()]),
+            ..Default::default()
+        };
+        let policy = HeaderPolicy::compile(&config, true).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-powered-by", HeaderValue::from_static("Express"));
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("SAMEORIGIN"));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        policy.apply(&mut headers);
+
+        assert!(headers.get("x-powered-by").is_none());
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[header::STRICT_TRANSPORT_SECURITY], "max-age=31536000");
+        assert_eq!(headers["x-env"], "dev");
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    }
+
+    #[test]
+    fn test_invalid_policies_are_rejected() {
+        let hsts = HeadersConfig { hsts: Some("max-age=60".to_string()), ..Default::default() };
+        assert!(HeaderPolicy::compile(&hsts, false).is_err());
+
+        let bad_name = HeadersConfig {
+            custom: Some(BTreeMap::from([("X Bad".to_string(), "1".to_string())])),
+            ..Default::default()
+        };
+        assert!(HeaderPolicy::compile(&bad_name, false).is_err());
+
+        let bad_value = HeadersConfig { referrer_policy: Some("no\nreferrer".to_string()), ..Default::default() };
+        assert!(HeaderPolicy::compile(&bad_value, false).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use axum::{extract::ConnectInfo, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use std::{future::Future, net::SocketAddr, pin::Pin, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+use tower::util::ServiceExt;
+use tracing::{debug, error, info, warn};
+
+use super::ssl::TlsConfig;
+#[cfg(feature = "ssl")]
+use super::tls::create_tls_acceptor;
+
+/// How a listener finished shutting down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainOutcome {
+    /// Every open connection finished on its own
+    Drained,
+    /// Connections were still open when the drain timeout expired and were closed
+    TimedOut,
+}
+
+pub async fn bind_listener(host: &str, port: u16) -> Result<TcpListener, Box<dyn std::error::Error + Send + Sync>> {
+    let addr: SocketAddr = format!("{}:{}", host, port).parse()
+        .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(e) })?;
+    let listener = TcpListener::bind(addr).await
+        .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(e) })?;
+    Ok(listener)
+}
+
+/// How long a client on a TLS port has to send its first bytes, and again to
+/// finish the TLS handshake, before the connection is dropped.
+#[cfg(feature = "ssl")]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The shutdown token of the listener a request came in on, for work that
+/// outlives the request such as upgraded connections.
+#[derive(Debug, Clone)]
+pub struct ListenerShutdown(pub CancellationToken);
+
+/// How a listener talks to clients. Without TLS or h2c it serves plain
+/// HTTP/1.1.
+#[derive(Clone, Default)]
+pub struct ListenerProtocol {
+    /// Serve HTTPS with these certificates, and HTTP/2 to clients that pick
+    /// it during the handshake (ALPN)
+    pub tls: Option<TlsConfig>,
+    /// Accept HTTP/2 with prior knowledge on a plain listener
+    pub h2c: bool,
+    /// Serve plain HTTP requests that arrive on a TLS listener with this
+    /// router instead of failing the handshake
+    pub plaintext: Option<Router>,
+}
+
+/// Accept connections until `shutdown` is cancelled, then stop accepting and
+/// give open connections up to `drain_timeout` to finish. In-flight requests
+/// complete normally; idle keep-alive connections are closed right away.
+pub async fn serve_listener(
+    listener: TcpListener,
+    app: Router,
+    protocol: ListenerProtocol,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+) -> Result<DrainOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let connections = accept_connections(listener, app, protocol, shutdown).await?;
+    Ok(drain_connections(connections, drain_timeout).await)
+}
+
+/// Accept connections until `shutdown` is cancelled and close the listener.
+/// Returns the connections still open, for [`drain_connections`].
+pub async fn accept_connections(
+    listener: TcpListener,
+    app: Router,
+    protocol: ListenerProtocol,
+    shutdown: CancellationToken,
+) -> Result<JoinSet<()>, Box<dyn std::error::Error + Send + Sync>> {
+    let ListenerProtocol { tls, h2c, plaintext } = protocol;
+    #[cfg(feature = "ssl")]
+    let tls_acceptor = match &tls {
+        Some(tls) => Some(create_tls_acceptor(tls, &shutdown).await.map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { e.to_string().into() })?),
+        None => None,
+    };
+    #[cfg(not(feature = "ssl"))]
+    if tls.is_some() {
+        return Err("HTTPS requested but SSL feature not enabled".into());
+    }
+    #[cfg(not(feature = "ssl"))]
+    let _ = plaintext;
+
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            accepted = listener.accept() => {
+                let (stream, addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let app = app.clone();
+                let shutdown = shutdown.clone();
+
+                #[cfg(feature = "ssl")]
+                if let Some(tls_acceptor) = &tls_acceptor {
+                    let tls_acceptor = tls_acceptor.clone();
+                    let plaintext = plaintext.clone();
+                    connections.spawn(async move {
+                        if let Some(plaintext) = plaintext {
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, is_plaintext_http(&stream)).await {
+                                Ok(true) => return serve_connection(stream, addr, plaintext, false, shutdown).await,
+                                Ok(false) => {}
+                                Err(_) => {
+                                    debug!("Client {} sent nothing, closing the connection", addr);
+                                    return;
+                                }
+                            }
+                        }
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream)).await {
+                            Ok(Ok(tls_stream)) => {
+                                let http2 = tls_stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                                serve_connection(tls_stream, addr, app, http2, shutdown).await
+                            }
+                            Ok(Err(err)) => error!("Failed to establish TLS connection: {}", err),
+                            Err(_) => debug!("TLS handshake with {} timed out, closing the connection", addr),
+                        }
+                    });
+                    continue;
+                }
+
+                connections.spawn(serve_connection(stream, addr, app, h2c, shutdown));
+            }
+        }
+    }
+
+    Ok(connections)
+}
+
+/// Whether a client on a TLS port started with a plain HTTP request. A TLS
+/// handshake starts with a 0x16 record type, HTTP with an uppercase method.
+#[cfg(feature = "ssl")]
+async fn is_plaintext_http(stream: &tokio::net::TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    matches!(stream.peek(&mut first).await, Ok(1) if first[0].is_ascii_uppercase())
+}
+
+/// Serve HTTP/1.1 on a connection, and also HTTP/2 if `http2` is set. The
+/// two are told apart by the first bytes the client sends.
+async fn serve_connection<I>(io: I, remote: SocketAddr, app: Router, http2: bool, shutdown: CancellationToken)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Handlers can see the client address, e.g. to pin it to a backend
+    let listener_shutdown = ListenerShutdown(shutdown.clone());
+    let service = hyper::service::service_fn(move |mut req: hyper::Request<hyper::body::Incoming>| {
+        req.extensions_mut().insert(ConnectInfo(remote));
+        req.extensions_mut().insert(listener_shutdown.clone());
+        app.clone().oneshot(req)
+    });
+
+    let io = TokioIo::new(io);
+    if http2 {
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let connection = builder.serve_connection_with_upgrades(io, service);
+        drive_connection(connection, |connection| connection.graceful_shutdown(), shutdown).await;
+    } else {
+        let connection = hyper::server::conn::http1::Builder::new()
+            .serve_connection(io, service)
+            .with_upgrades();
+        drive_connection(connection, |connection| connection.graceful_shutdown(), shutdown).await;
+    }
+}
+
+/// Run a connection to completion, asking it to wind down once `shutdown`
+/// is cancelled.
+async fn drive_connection<C, E>(connection: C, graceful_shutdown: impl FnOnce(Pin<&mut C>), shutdown: CancellationToken)
+where
+    C: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            graceful_shutdown(connection.as_mut());
+            connection.as_mut().await
+        }
+    };
+
+    if let Err(err) = result {
+        debug!("Failed to serve connection: {}", err);
+    }
+}
+
+/// Give open connections up to `drain_timeout` to finish, then close them.
+pub async fn drain_connections(mut connections: JoinSet<()>, drain_timeout: Duration) -> DrainOutcome {
+    if connections.is_empty() {
+        return DrainOutcome::Drained;
+    }
+
+    info!("⏳ Draining {} open connection(s)...", connections.len());
+    let drained = tokio::time::timeout(drain_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    match drained {
+        Ok(()) => DrainOutcome::Drained,
+        Err(_) => {
+            warn!("⚠️  Drain timeout reached, closing {} connection(s)", connections.len());
+            connections.abort_all();
+            DrainOutcome::TimedOut
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "ssl")]
+    use crate::server::testing::TempSite;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_requests() {
+        let app = Router::new().route("/slow", get(|| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            "done"
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_listener(listener, app, ListenerProtocol::default(), shutdown.clone(), Duration::from_secs(5)));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.cancel();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("done"));
+        assert_eq!(server.await.unwrap().unwrap(), DrainOutcome::Drained);
+
+        // The listener is closed once shutdown starts
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_closes_connections() {
+        let app = Router::new().route("/hang", get(std::future::pending::<&'static str>));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_listener(listener, app, ListenerProtocol::default(), shutdown.clone(), Duration::from_millis(100)));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /hang HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.cancel();
+
+        assert_eq!(server.await.unwrap().unwrap(), DrainOutcome::TimedOut);
+    }
+
+    /// Send `GET /` as HTTP/2 with prior knowledge.
+    async fn get_h2c(addr: SocketAddr) -> Result<hyper::Response<hyper::body::Incoming>, hyper::Error> {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+        let req = hyper::Request::get(format!("http://{}/", addr)).body(axum::body::Body::empty()).unwrap();
+        sender.send_request(req).await
+    }
+
+    #[tokio::test]
+    async fn test_h2c_with_prior_knowledge() {
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let h2c = ListenerProtocol { h2c: true, ..Default::default() };
+        tokio::spawn(serve_listener(listener, app.clone(), h2c, shutdown.clone(), Duration::from_secs(1)));
+
+        let response = get_h2c(addr).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.status(), 200);
+
+        // HTTP/1.1 clients still work on the same port
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        // Without h2c the HTTP/2 preface is a bad HTTP/1.1 request
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plain = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, app, ListenerProtocol::default(), shutdown.clone(), Duration::from_secs(1)));
+        assert!(get_h2c(plain).await.is_err());
+
+        shutdown.cancel();
+    }
+
+    /// Serve TLS with a self-signed certificate written to `site`.
+    #[cfg(feature = "ssl")]
+    fn tls_protocol(site: &TempSite, plaintext: Option<Router>) -> ListenerProtocol {
+        use crate::server::ssl::{CertFiles, CertOptions, SiteCert};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let files = CertFiles {
+            cert: site.write("cert.pem", cert.serialize_pem().unwrap()),
+            key: site.write("key.pem", cert.serialize_private_key_pem()),
+        };
+
+        ListenerProtocol {
+            tls: Some(TlsConfig {
+                certs: vec![SiteCert {
+                    hostnames: Vec::new(),
+                    name: "localhost".to_string(),
+                    names: Vec::new(),
+                    options: CertOptions::default(),
+                    files: Some(files),
+                }],
+                default: 0,
+            }),
+            h2c: false,
+            plaintext,
+        }
+    }
+
+    #[cfg(feature = "ssl")]
+    #[tokio::test]
+    async fn test_plaintext_on_tls_port_is_redirected() {
+        use crate::server::redirect::redirect_to_https;
+
+        let site = TempSite::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let protocol = tls_protocol(&site, Some(redirect_to_https(addr.port())));
+        let shutdown = CancellationToken::new();
+        let app = Router::new().route("/", get(|| async { "secure" }));
+        tokio::spawn(serve_listener(listener, app, protocol, shutdown.clone(), Duration::from_secs(1)));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET /docs HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", addr.port());
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 301"));
+        assert!(response.contains(&format!("location: https://localhost:{}/docs", addr.port())));
+
+        shutdown.cancel();
+    }
+
+    #[cfg(feature = "ssl")]
+    #[tokio::test]
+    async fn test_silent_client_on_tls_port_is_dropped() {
+        use crate::server::redirect::redirect_to_https;
+
+        let site = TempSite::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let protocol = tls_protocol(&site, Some(redirect_to_https(addr.port())));
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_listener(listener, Router::new(), protocol, shutdown.clone(), Duration::from_secs(30)));
+
+        // A client that never sends anything is closed, and doesn't hold up shutdown
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.cancel();
+        let closed = tokio::time::timeout(HANDSHAKE_TIMEOUT * 2, stream.read(&mut [0u8; 1])).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+        assert_eq!(server.await.unwrap().unwrap(), DrainOutcome::Drained);
+    }
+
+    #[cfg(feature = "ssl")]
+    #[tokio::test]
+    async fn test_stalled_tls_handshake_is_dropped() {
+        let site = TempSite::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_listener(listener, Router::new(), tls_protocol(&site, None), shutdown.clone(), Duration::from_secs(30)));
+
+        // Start a TLS record and never finish it
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0x16, 0x03, 0x01]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.cancel();
+        let closed = tokio::time::timeout(HANDSHAKE_TIMEOUT * 2, stream.read(&mut [0u8; 1])).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+        assert_eq!(server.await.unwrap().unwrap(), DrainOutcome::Drained);
+    }
+}
@@ -0,0 +1,350 @@
+//! Directory listings for sites with `directory_listing = true`: folders
+//! without an `index.html` are shown as an HTML page with breadcrumbs and
+//! sortable columns, or as JSON for clients that ask for it. Dotfiles are
+//! left out.
+//!
+//! Listed paths are resolved segment by segment and checked against the
+//! canonical site root, so neither `..` nor a symlink can list a folder
+//! outside it.
+
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Characters escaped in the links of a listing
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    const ALL: [SortKey; 3] = [SortKey::Name, SortKey::Size, SortKey::Modified];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SortKey::Name => "Name",
+            SortKey::Size => "Size",
+            SortKey::Modified => "Modified",
+        }
+    }
+}
+
+/// `?sort=name|size|modified&order=asc|desc`, by name ascending if unset.
+#[derive(Debug, Clone, Copy)]
+struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Sort {
+    fn from_query(query: Option<&str>) -> Self {
+        let mut sort = Sort { key: SortKey::Name, descending: false };
+        for (name, value) in query.unwrap_or_default().split('&').filter_map(|pair| pair.split_once('=')) {
+            match name {
+                "sort" => {
+                    if let Some(key) = SortKey::ALL.into_iter().find(|key| key.as_str() == value) {
+                        sort.key = key;
+                    }
+                }
+                "order" => sort.descending = value == "desc",
+                _ => {}
+            }
+        }
+        sort
+    }
+
+    /// Folders always come first.
+    fn apply(self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| {
+            let order = match self.key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+            }
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+            let order = if self.descending { order.reverse() } else { order };
+            b.is_dir.cmp(&a.is_dir).then(order)
+        });
+    }
+
+    /// Query of a column header link: sorting by the current column again
+    /// flips the order.
+    fn link(self, key: SortKey) -> String {
+        let order = if key == self.key && !self.descending { "desc" } else { "asc" };
+        format!("?sort={}&order={}", key.as_str(), order)
+    }
+}
+
+/// The listing of the folder requested by `uri` under `root`, or `None`
+/// when it isn't a folder inside the root.
+pub async fn list_directory(root: &Path, uri: &Uri, headers: &HeaderMap) -> Option<Response> {
+    let relative = relative_path(uri.path())?;
+    let root = tokio::fs::canonicalize(root).await.ok()?;
+    let dir = tokio::fs::canonicalize(root.join(relative)).await.ok()?;
+    if !dir.starts_with(&root) || !dir.is_dir() {
+        return None;
+    }
+
+    let mut entries = read_entries(&dir).await.ok()?;
+    let sort = Sort::from_query(uri.query());
+    sort.apply(&mut entries);
+
+    let response = if wants_json(headers) {
+        Json(json_listing(uri.path(), &entries)).into_response()
+    } else {
+        let html = html_listing(uri.path(), &entries, sort);
+        (StatusCode::OK, [(header::CONTENT_TYPE, "text/html; charset=utf-8")], html).into_response()
+    };
+    Some(response)
+}
+
+/// The request path as a path relative to the root, refusing anything that
+/// could step out of it.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/').filter(|segment| !segment.is_empty()) {
+        let unsafe_segment = segment == "." || segment == ".." || segment.contains(['\\', ':', '\0']);
+        if unsafe_segment {
+            return None;
+        }
+        relative.push(segment);
+    }
+    Some(relative)
+}
+
+async fn read_entries(dir: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follows symlinks; broken ones are left out
+        let Ok(metadata) = tokio::fs::metadata(entry.path()).await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+fn wants_json(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+fn json_listing(path: &str, entries: &[Entry]) -> serde_json::Value {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": (!entry.is_dir).then_some(entry.size),
+                "modified": entry.modified.and_then(unix_seconds),
+            })
+        })
+        .collect();
+    serde_json::json!({ "path": path, "entries": entries })
+}
+
+fn html_listing(path: &str, entries: &[Entry], sort: Sort) -> String {
+    let title = html_escape(&percent_decode_str(path).decode_utf8_lossy());
+
+    let mut breadcrumbs = String::from(r#"<a href="/">/</a>"#);
+    let mut href = String::from("/");
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        href.push_str(segment);
+        href.push('/');
+        let name = html_escape(&percent_decode_str(segment).decode_utf8_lossy());
+        breadcrumbs.push_str(&format!(r#" <a href="{}">{}</a> /"#, html_escape(&href), name));
+    }
+
+    let headings: String = SortKey::ALL
+        .into_iter()
+        .map(|key| {
+            let arrow = match (key == sort.key, sort.descending) {
+                (true, false) => " ▲",
+                (true, true) => " ▼",
+                (false, _) => "",
+            };
+            format!(r#"<th><a href="{}">{}{}</a></th>"#, html_escape(&sort.link(key)), key.label(), arrow)
+        })
+        .collect();
+
+    let mut rows = String::new();
+    if path != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let href = format!("{}{}", utf8_percent_encode(&entry.name, PATH_SEGMENT), suffix);
+        let size = if entry.is_dir { "-".to_string() } else { human_size(entry.size) };
+        let modified = entry.modified.map(httpdate::fmt_http_date).unwrap_or_default();
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            html_escape(&href),
+            html_escape(&entry.name),
+            suffix,
+            size,
+            modified
+        ));
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Index of {title}</title>
+<style>
+body {{ font-family: system-ui, sans-serif; margin: 2rem; }}
+table {{ border-collapse: collapse; }}
+th, td {{ padding: 0.25rem 1.5rem 0.25rem 0; text-align: left; }}
+td:nth-child(2) {{ text-align: right; }}
+</style>
+</head>
+<body>
+<h1>Index of {breadcrumbs}</h1>
+<table>
+<tr>{headings}</tr>
+{rows}</table>
+</body>
+</html>
+"#
+    )
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn unix_seconds(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::TempSite;
+    use axum::http::HeaderValue;
+
+    fn temp_site() -> TempSite {
+        let site = TempSite::new();
+        std::fs::create_dir_all(site.root.join("docs/guides")).unwrap();
+        site.write("docs/a.txt", "a");
+        site.write("docs/big <file>.bin", vec![0u8; 2048]);
+        site.write("docs/.env", "SECRET=1");
+        site
+    }
+
+    async fn json(root: &Path, uri: &str) -> Option<serde_json::Value> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+        let response = list_directory(root, &uri.parse().unwrap(), &headers).await?;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        Some(serde_json::from_slice(&body).unwrap())
+    }
+
+    fn names(listing: &serde_json::Value) -> Vec<&str> {
+        listing["entries"].as_array().unwrap().iter().map(|entry| entry["name"].as_str().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_listing_sorts_and_hides_dotfiles() {
+        let site = temp_site();
+        let root = &site.root;
+
+        let listing = json(root, "/docs/").await.unwrap();
+        assert_eq!(names(&listing), ["guides", "a.txt", "big <file>.bin"]);
+        assert_eq!(listing["entries"][0]["type"], "directory");
+        assert_eq!(listing["entries"][2]["size"], 2048);
+
+        let listing = json(root, "/docs/?sort=size&order=desc").await.unwrap();
+        assert_eq!(names(&listing), ["guides", "big <file>.bin", "a.txt"]);
+
+        let response = list_directory(root, &"/docs/".parse().unwrap(), &HeaderMap::new()).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains(r#"<a href="big%20%3Cfile%3E.bin">big &lt;file&gt;.bin</a>"#));
+        assert!(html.contains(r#"<a href="/docs/">docs</a>"#));
+        assert!(html.contains("2.0 KB"));
+    }
+
+    #[tokio::test]
+    async fn test_listing_stays_inside_the_root() {
+        let site = temp_site();
+        let docs = site.root.join("docs");
+
+        assert!(json(&docs, "/guides/").await.is_some());
+        assert!(json(&docs, "/../").await.is_none());
+        assert!(json(&docs, "/%2e%2e/").await.is_none());
+        assert!(json(&docs, "/guides/..%2f..%2f/").await.is_none());
+        assert!(json(&docs, "/missing/").await.is_none());
+        assert!(json(&docs, "/a.txt/").await.is_none());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&site.root, docs.join("outside")).unwrap();
+            assert!(json(&docs, "/outside/").await.is_none());
+        }
+    }
+}
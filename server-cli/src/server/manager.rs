@@ -0,0 +1,733 @@
+use axum::{extract::Request, Router};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::Mutex,
+    task::{JoinHandle, JoinSet},
+};
+use tokio_util::sync::CancellationToken;
+use tower::util::ServiceExt;
+use tracing::{error, info, warn};
+
+use super::{
+    build_router,
+    listener::{accept_connections, bind_listener, drain_connections, DrainOutcome},
+    redirect::accept_redirects,
+    AppState, VirtualHosts,
+};
+use crate::config::{group_sites_by_port, RestartPolicy, SiteConfig, SiteGroup, SiteUrls};
+use crate::events::{self, Event};
+use crate::network::NetworkInfo;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManagerError {
+    #[error("Site '{0}' not found")]
+    NotFound(String),
+    #[error("Site '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("{0}")]
+    InvalidConfig(String),
+}
+
+/// Longest wait between two restarts of a failed listener
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// A restarted listener that stays up this long is stable again, so a later
+/// failure starts counting restarts from zero
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SiteState {
+    Running,
+    Stopped,
+    Failed,
+}
+
+/// Snapshot of a managed site, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct SiteStatus {
+    pub name: String,
+    pub root: PathBuf,
+    pub port: u16,
+    pub https: bool,
+    pub proxy_to: Option<u16>,
+    pub hostnames: Vec<String>,
+    pub default: bool,
+    pub status: SiteState,
+    /// Why the site's listener is down, while its status is `failed`
+    pub error: Option<String>,
+    pub urls: SiteUrls,
+}
+
+/// Router that can be replaced while its listener keeps running, so changed
+/// sites pick up new settings without dropping open connections.
+#[derive(Clone)]
+struct SharedRouter(Arc<RwLock<Router>>);
+
+impl SharedRouter {
+    fn new(router: Router) -> Self {
+        Self(Arc::new(RwLock::new(router)))
+    }
+
+    fn replace(&self, router: Router) {
+        *self.0.write().unwrap() = router;
+    }
+
+    fn service(&self) -> Router {
+        let shared = self.clone();
+        Router::new().fallback(move |req: Request| {
+            let router = shared.0.read().unwrap().clone();
+            async move {
+                match router.oneshot(req).await {
+                    Ok(response) => response,
+                    Err(never) => match never {},
+                }
+            }
+        })
+    }
+}
+
+struct RunningListener {
+    id: u64,
+    group: SiteGroup,
+    router: SharedRouter,
+    shutdown: CancellationToken,
+    /// Cancelled once the listener's ports are closed, while open
+    /// connections may still be draining
+    closed: CancellationToken,
+    task: JoinHandle<DrainOutcome>,
+    /// Restarts it took to get this listener up, carried over if it fails
+    /// again before [`STABLE_UPTIME`]
+    restarts: u32,
+    started: Instant,
+}
+
+/// A port whose listener couldn't start or stopped with an error.
+struct FailedListener {
+    id: u64,
+    group: SiteGroup,
+    error: String,
+    restarts: u32,
+    restarting: bool,
+}
+
+#[derive(Default)]
+struct ManagerState {
+    sites: Vec<SiteConfig>,
+    stopped: HashSet<String>,
+    listeners: HashMap<u16, RunningListener>,
+    failed: HashMap<u16, FailedListener>,
+    next_id: u64,
+}
+
+impl ManagerState {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+/// Owns the configured sites and one listener per port, and keeps the
+/// listeners in line with the sites that should be running.
+///
+/// A port whose listener fails doesn't affect the others: its sites are
+/// reported as failed and, if one of them has the `on-failure` restart
+/// policy, the listener is started again with exponential backoff.
+pub struct SiteManager {
+    host: String,
+    network: NetworkInfo,
+    drain_timeout: Duration,
+    state: Mutex<ManagerState>,
+    this: Weak<SiteManager>,
+}
+
+impl SiteManager {
+    /// Create a manager binding on `host`. `network` is used to report the
+    /// URLs of each site and stopped listeners get `drain_timeout` to finish
+    /// open connections.
+    pub fn new(host: &str, network: NetworkInfo, drain_timeout: Duration) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            host: host.to_string(),
+            network,
+            drain_timeout,
+            state: Mutex::new(ManagerState::default()),
+            this: this.clone(),
+        })
+    }
+
+    /// Replace the configured sites. Listeners for new ports are started,
+    /// listeners whose sites were removed are stopped and the router of
+    /// listeners whose sites changed is rebuilt. Unchanged listeners are
+    /// left alone. Sites stopped through [`SiteManager::stop_site`] stay
+    /// stopped.
+    pub async fn apply(&self, sites: Vec<SiteConfig>) -> Result<(), ManagerError> {
+        self.change(|state| {
+            state.stopped.retain(|name| sites.iter().any(|s| &s.name == name));
+            state.sites = sites;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn add_site(&self, site: SiteConfig) -> Result<(), ManagerError> {
+        self.change(|state| {
+            if state.sites.iter().any(|s| s.name == site.name) {
+                return Err(ManagerError::AlreadyExists(site.name));
+            }
+            state.sites.push(site);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove_site(&self, name: &str) -> Result<(), ManagerError> {
+        self.change(|state| {
+            let index = find_site(&state.sites, name)?;
+            state.sites.remove(index);
+            state.stopped.remove(name);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Start a stopped site. Starting a failed site retries its listener
+    /// right away.
+    pub async fn start_site(&self, name: &str) -> Result<(), ManagerError> {
+        self.change(|state| {
+            let port = state.sites[find_site(&state.sites, name)?].port;
+            state.stopped.remove(name);
+            state.failed.remove(&port);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn stop_site(&self, name: &str) -> Result<(), ManagerError> {
+        self.change(|state| {
+            find_site(&state.sites, name)?;
+            state.stopped.insert(name.to_string());
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn sites(&self) -> Vec<SiteStatus> {
+        let state = self.state.lock().await;
+        state
+            .sites
+            .iter()
+            .map(|site| {
+                let failure = state
+                    .failed
+                    .get(&site.port)
+                    .filter(|failed| failed.group.sites.iter().any(|s| s.name == site.name));
+                let status = if state.stopped.contains(&site.name) {
+                    SiteState::Stopped
+                } else if failure.is_some() {
+                    SiteState::Failed
+                } else {
+                    SiteState::Running
+                };
+
+                SiteStatus {
+                    name: site.name.clone(),
+                    root: site.root.clone(),
+                    port: site.port,
+                    https: site.https,
+                    proxy_to: site.proxy_to,
+                    hostnames: site.hostnames.clone(),
+                    default: site.default_host,
+                    status,
+                    error: failure.map(|failed| failed.error.clone()),
+                    urls: site.urls(&self.network),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether nothing is being served and no failed listener will be
+    /// restarted on its own.
+    pub async fn is_idle(&self) -> bool {
+        let state = self.state.lock().await;
+        state.listeners.is_empty() && !state.failed.values().any(|failed| failed.restarting)
+    }
+
+    pub async fn site(&self, name: &str) -> Result<SiteStatus, ManagerError> {
+        self.sites()
+            .await
+            .into_iter()
+            .find(|site| site.name == name)
+            .ok_or_else(|| ManagerError::NotFound(name.to_string()))
+    }
+
+    /// Stop every listener, draining open connections. Returns
+    /// [`DrainOutcome::TimedOut`] if any listener had to cut connections.
+    pub async fn shutdown(&self) -> DrainOutcome {
+        let mut state = self.state.lock().await;
+        let running = running_sites(&state);
+
+        // Forgetting failed listeners also cancels their pending restarts
+        state.failed.clear();
+        let listeners: Vec<RunningListener> = state.listeners.drain().map(|(_, l)| l).collect();
+        let outcomes = futures::future::join_all(listeners.into_iter().map(stop_listener)).await;
+
+        self.emit_site_changes(&running, &[]);
+        if outcomes.contains(&DrainOutcome::TimedOut) {
+            DrainOutcome::TimedOut
+        } else {
+            DrainOutcome::Drained
+        }
+    }
+
+    /// Modify the configured sites and reconcile the listeners. If the new
+    /// sites are invalid, the previous ones are restored. Listeners that
+    /// fail to start are reported through the status of their sites.
+    async fn change(
+        &self,
+        modify: impl FnOnce(&mut ManagerState) -> Result<(), ManagerError>,
+    ) -> Result<(), ManagerError> {
+        let mut state = self.state.lock().await;
+        let previous = (state.sites.clone(), state.stopped.clone());
+        let running = running_sites(&state);
+
+        modify(&mut state)?;
+        let mut stopping = Vec::new();
+        let result = self.reconcile(&mut state, &mut stopping).await;
+        if result.is_err() {
+            (state.sites, state.stopped) = previous;
+        }
+        let after = running_sites(&state);
+
+        // Open connections of stopped listeners drain without holding up
+        // the admin API or other changes
+        drop(state);
+        futures::future::join_all(stopping.into_iter().map(stop_listener)).await;
+
+        self.emit_site_changes(&running, &after);
+        result
+    }
+
+    fn emit_site_changes(&self, before: &[SiteConfig], after: &[SiteConfig]) {
+        for site in before.iter().filter(|site| !after.iter().any(|s| s.name == site.name)) {
+            events::emit(Event::SiteStopped { site: &site.name, port: site.port });
+        }
+        for site in after.iter().filter(|site| !before.contains(site)) {
+            let urls = site.urls(&self.network);
+            events::emit(Event::SiteStarted {
+                site: &site.name,
+                port: site.port,
+                https: site.https,
+                urls: &urls,
+            });
+        }
+    }
+
+    /// Bring the listeners in line with the active sites. Listeners that
+    /// have to go are closed and moved to `stopping`, left for the caller
+    /// to drain.
+    async fn reconcile(&self, state: &mut ManagerState, stopping: &mut Vec<RunningListener>) -> Result<(), ManagerError> {
+        let active: Vec<SiteConfig> = state
+            .sites
+            .iter()
+            .filter(|site| !state.stopped.contains(&site.name))
+            .cloned()
+            .collect();
+        let groups = group_sites_by_port(&active).map_err(ManagerError::InvalidConfig)?;
+
+        state.failed.retain(|port, _| groups.iter().any(|g| g.port == *port));
+        let removed: Vec<u16> = state
+            .listeners
+            .keys()
+            .filter(|port| !groups.iter().any(|g| g.port == **port))
+            .copied()
+            .collect();
+        for port in removed {
+            if let Some(running) = state.listeners.remove(&port) {
+                stopping.push(close_listener(running).await);
+                info!("🛑 Stopped listener on port {}", port);
+            }
+        }
+
+        for group in groups {
+            let port = group.port;
+            match state.listeners.get_mut(&port) {
+                Some(running) if running.group.sites == group.sites => continue,
+                // A new certificate or protocol needs a new listener
+                Some(running) if running.group.same_listener(&group, &self.network) => {
+                    match build_group_router(&group, &self.host).await {
+                        Ok(router) => {
+                            running.router.replace(router);
+                            running.group = group;
+                            info!("🔁 Reloaded sites on port {}", port);
+                        }
+                        Err(e) => {
+                            if let Some(running) = state.listeners.remove(&port) {
+                                stopping.push(close_listener(running).await);
+                            }
+                            self.record_failure(state, group, e.to_string(), 0);
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            // A failed listener for the same sites keeps its pending restart
+            if state.failed.get(&port).is_some_and(|failed| failed.group.sites == group.sites) {
+                continue;
+            }
+            state.failed.remove(&port);
+            // The ports have to be free before the new listener binds them
+            if let Some(running) = state.listeners.remove(&port) {
+                stopping.push(close_listener(running).await);
+            }
+            let id = state.next_id();
+            match self.start_listener(id, group.clone(), 0).await {
+                Ok(running) => {
+                    state.listeners.insert(port, running);
+                }
+                Err(e) => self.record_failure(state, group, e.to_string(), 0),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn start_listener(&self, id: u64, group: SiteGroup, restarts: u32) -> Result<RunningListener, BoxError> {
+        let router = SharedRouter::new(build_group_router(&group, &self.host).await?);
+        let listener = bind_listener(&self.host, group.port).await?;
+        let redirect_listener = match group.redirect_http_port {
+            Some(redirect_port) => Some(bind_listener(&self.host, redirect_port).await?),
+            None => None,
+        };
+
+        let app = router.service();
+        let protocol = group.listener_protocol(&self.network);
+        let port = group.port;
+        let shutdown = CancellationToken::new();
+        let closed = CancellationToken::new();
+        let manager = self.this.clone();
+        let drain_timeout = self.drain_timeout;
+        let listener_shutdown = shutdown.clone();
+        let listener_closed = closed.clone().drop_guard();
+        let task = tokio::spawn(async move {
+            // The redirect listener lives as long as the one it points to
+            let redirect_shutdown = listener_shutdown.child_token();
+            let redirect = redirect_listener
+                .map(|listener| tokio::spawn(accept_redirects(listener, port, redirect_shutdown.clone())));
+            let result = accept_connections(listener, app, protocol, listener_shutdown).await;
+            redirect_shutdown.cancel();
+            let redirect_connections = match redirect {
+                Some(redirect) => redirect.await.ok().flatten().unwrap_or_default(),
+                None => JoinSet::new(),
+            };
+            drop(listener_closed);
+
+            let connections = match result {
+                Ok(connections) => connections,
+                Err(e) => {
+                    // Handled on its own task: the manager may be holding its
+                    // lock while it waits for this one to close
+                    let error = e.to_string();
+                    tokio::spawn(async move {
+                        if let Some(manager) = manager.upgrade() {
+                            manager.listener_failed(port, id, error).await;
+                        }
+                    });
+                    JoinSet::new()
+                }
+            };
+            let (outcome, _) = futures::future::join(
+                drain_connections(connections, drain_timeout),
+                drain_connections(redirect_connections, drain_timeout),
+            )
+            .await;
+            outcome
+        });
+
+        Ok(RunningListener {
+            id,
+            group,
+            router,
+            shutdown,
+            closed,
+            task,
+            restarts,
+            started: Instant::now(),
+        })
+    }
+
+    /// Mark the sites of a listener as failed and schedule a restart if
+    /// their restart policy asks for one.
+    fn record_failure(&self, state: &mut ManagerState, group: SiteGroup, error: String, restarts: u32) {
+        let id = state.next_id();
+        let port = group.port;
+        let restarting = should_restart(&group, restarts);
+
+        error!("❌ Sites on port {} failed: {}", port, error);
+        for site in &group.sites {
+            events::emit(Event::SiteFailed { site: &site.name, port, error: &error, restarting });
+        }
+
+        if restarting {
+            let delay = restart_delay(restarts);
+            warn!("🔁 Restarting port {} in {}s", port, delay.as_secs());
+            let manager = self.this.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                if let Some(manager) = manager.upgrade() {
+                    manager.restart(port, id).await;
+                }
+            });
+        }
+
+        state.failed.insert(port, FailedListener { id, group, error, restarts, restarting });
+    }
+
+    async fn listener_failed(&self, port: u16, id: u64, error: String) {
+        let mut state = self.state.lock().await;
+        // The listener may have been stopped or replaced in the meantime
+        if state.listeners.get(&port).map(|running| running.id) != Some(id) {
+            return;
+        }
+        if let Some(running) = state.listeners.remove(&port) {
+            let restarts = restarts_after(running.restarts, running.started.elapsed());
+            self.record_failure(&mut state, running.group, error, restarts);
+        }
+    }
+
+    async fn restart(&self, port: u16, id: u64) {
+        let mut state = self.state.lock().await;
+        // Skip restarts that were superseded by a config change or shutdown
+        if state.failed.get(&port).map(|failed| failed.id) != Some(id) {
+            return;
+        }
+        let Some(failed) = state.failed.remove(&port) else { return };
+
+        let restarts = failed.restarts + 1;
+        let id = state.next_id();
+        match self.start_listener(id, failed.group.clone(), restarts).await {
+            Ok(running) => {
+                info!("✅ Restarted sites on port {}", port);
+                self.emit_site_changes(&[], &running.group.sites);
+                state.listeners.insert(port, running);
+            }
+            Err(e) => self.record_failure(&mut state, failed.group, e.to_string(), restarts),
+        }
+    }
+}
+
+/// A failed listener is restarted if any of its sites asks for it and that
+/// site hasn't used up its restarts.
+fn should_restart(group: &SiteGroup, restarts: u32) -> bool {
+    group.sites.iter().any(|site| {
+        site.restart == RestartPolicy::OnFailure && site.max_restarts.is_none_or(|max| restarts < max)
+    })
+}
+
+/// The restarts counted against a listener that failed after `uptime`.
+fn restarts_after(restarts: u32, uptime: Duration) -> u32 {
+    if uptime >= STABLE_UPTIME {
+        0
+    } else {
+        restarts
+    }
+}
+
+/// Wait 1s before the first restart, doubling up to [`MAX_RESTART_DELAY`].
+fn restart_delay(restarts: u32) -> Duration {
+    Duration::from_secs(1u64 << restarts.min(6)).min(MAX_RESTART_DELAY)
+}
+
+fn running_sites(state: &ManagerState) -> Vec<SiteConfig> {
+    state
+        .listeners
+        .values()
+        .flat_map(|running| running.group.sites.iter().cloned())
+        .collect()
+}
+
+fn find_site(sites: &[SiteConfig], name: &str) -> Result<usize, ManagerError> {
+    sites
+        .iter()
+        .position(|site| site.name == name)
+        .ok_or_else(|| ManagerError::NotFound(name.to_string()))
+}
+
+/// Stop accepting connections and wait until the listener's ports are closed,
+/// leaving open connections to [`stop_listener`].
+async fn close_listener(running: RunningListener) -> RunningListener {
+    running.shutdown.cancel();
+    running.closed.cancelled().await;
+    running
+}
+
+/// Stop accepting connections and wait for open ones to drain.
+async fn stop_listener(running: RunningListener) -> DrainOutcome {
+    running.shutdown.cancel();
+    running.task.await.unwrap_or(DrainOutcome::Drained)
+}
+
+/// Build the router for every site in a group: the site's own router when it
+/// has the port to itself, otherwise a Host-header dispatcher.
+async fn build_group_router(group: &SiteGroup, host: &str) -> Result<Router, BoxError> {
+    let protocol = if group.https { "https" } else { "http" };
+
+    if let [site] = group.sites.as_slice() {
+        let router = build_site_router(site).await?;
+        info!("   📁 {} → {}://{}:{}", site.name, protocol, host, site.port);
+        if let Some(proxy_port) = site.proxy_to {
+            info!("   🔄 {} proxying API → localhost:{}", site.name, proxy_port);
+        }
+        return Ok(router);
+    }
+
+    let mut hosts = VirtualHosts::new();
+    for site in &group.sites {
+        let router = build_site_router(site).await?;
+
+        for hostname in &site.hostnames {
+            hosts.add(hostname, router.clone());
+            info!("   📁 {} → {}://{}:{}", site.name, protocol, hostname, group.port);
+        }
+        if site.default_host {
+            hosts.set_default(router);
+            info!("   ⭐ {} is the default site on port {}", site.name, group.port);
+        }
+
+        if let Some(proxy_port) = site.proxy_to {
+            info!("   🔄 {} proxying API → localhost:{}", site.name, proxy_port);
+        }
+    }
+
+    Ok(hosts.into_router())
+}
+
+async fn build_site_router(site: &SiteConfig) -> Result<Router, BoxError> {
+    let state = Arc::new(AppState::new(site.server_config()));
+    build_router(state).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &str, port: u16, restart: RestartPolicy) -> SiteConfig {
+        SiteConfig {
+            name: name.to_string(),
+            root: PathBuf::from("."),
+            port,
+            restart,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_site_does_not_stop_others() {
+        let blocker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = blocker.local_addr().unwrap().port();
+        let manager = SiteManager::new("127.0.0.1", NetworkInfo::default(), Duration::from_secs(1));
+
+        manager
+            .apply(vec![site("blocked", taken, RestartPolicy::Never), site("healthy", 0, RestartPolicy::Never)])
+            .await
+            .unwrap();
+
+        let blocked = manager.site("blocked").await.unwrap();
+        assert_eq!(blocked.status, SiteState::Failed);
+        assert!(blocked.error.is_some());
+        assert_eq!(manager.site("healthy").await.unwrap().status, SiteState::Running);
+        assert!(!manager.is_idle().await);
+    }
+
+    #[tokio::test]
+    async fn test_failed_site_restarts_on_failure() {
+        let blocker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = blocker.local_addr().unwrap().port();
+        let manager = SiteManager::new("127.0.0.1", NetworkInfo::default(), Duration::from_secs(1));
+
+        manager.apply(vec![site("retry", port, RestartPolicy::OnFailure)]).await.unwrap();
+        assert_eq!(manager.site("retry").await.unwrap().status, SiteState::Failed);
+        assert!(!manager.is_idle().await);
+
+        drop(blocker);
+        tokio::time::sleep(restart_delay(0) + Duration::from_millis(500)).await;
+
+        let site = manager.site("retry").await.unwrap();
+        assert_eq!(site.status, SiteState::Running);
+        assert_eq!(site.error, None);
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_removed_site_drains_without_holding_the_lock() {
+        use tokio::io::AsyncWriteExt;
+
+        // A backend that never answers keeps the proxied request in flight
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = backend.accept().await {
+                held.push(stream);
+            }
+        });
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let manager = SiteManager::new("127.0.0.1", NetworkInfo::default(), Duration::from_secs(5));
+        let mut slow = site("slow", port, RestartPolicy::Never);
+        slow.proxy_to = Some(backend_port);
+        manager.apply(vec![slow, site("other", 0, RestartPolicy::Never)]).await.unwrap();
+
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client.write_all(b"GET /api/slow HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let removing = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.remove_site("slow").await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!removing.is_finished());
+        let sites = tokio::time::timeout(Duration::from_secs(1), manager.sites()).await.unwrap();
+        assert_eq!(sites.len(), 1);
+        // The port is closed even though the request is still draining
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+        drop(client);
+        removing.await.unwrap().unwrap();
+        manager.shutdown().await;
+    }
+
+    #[test]
+    fn test_restart_backoff() {
+        assert_eq!(restart_delay(0), Duration::from_secs(1));
+        assert_eq!(restart_delay(3), Duration::from_secs(8));
+        assert_eq!(restart_delay(20), MAX_RESTART_DELAY);
+
+        let mut limited = site("a", 80, RestartPolicy::OnFailure);
+        limited.max_restarts = Some(2);
+        let group = SiteGroup {
+            port: 80,
+            https: false,
+            h2c: false,
+            redirect_http_port: None,
+            redirect_plaintext: false,
+            sites: vec![limited],
+        };
+        assert!(should_restart(&group, 1));
+        assert!(!should_restart(&group, 2));
+
+        // A listener that ran long enough gets its restarts back
+        assert_eq!(restarts_after(2, Duration::from_secs(5)), 2);
+        assert_eq!(restarts_after(2, STABLE_UPTIME), 0);
+        assert!(should_restart(&group, restarts_after(2, STABLE_UPTIME + Duration::from_secs(1))));
+    }
+}
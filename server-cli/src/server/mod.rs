@@ -1,26 +1,112 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
+use crate::config::{CacheConfig, CompressionConfig, CorsConfig, HeadersConfig, RouteConfig, UpstreamConfig};
+
+#[cfg_attr(not(feature = "ssl"), allow(dead_code))]
 pub mod ssl;
+#[cfg(feature = "ssl")]
+pub mod tls;
 pub mod proxy;
+pub mod tunnel;
+pub mod routes;
+pub mod headers;
+pub mod cors;
+pub mod compression;
+pub mod cache;
+pub mod listing;
+pub mod error_pages;
+pub mod upstream;
+pub mod vhost;
+pub mod router;
+pub mod listener;
+pub mod redirect;
+pub mod manager;
+pub mod watch;
+pub mod admin;
+pub mod shutdown;
+
+pub use proxy::proxy_to_group;
+pub use vhost::VirtualHosts;
+pub use router::build_router;
+pub use manager::SiteManager;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ServerConfig {
+    pub name: String,
     pub root_dir: PathBuf,
-    pub port: u16,
-    pub host: String,
     pub https_enabled: bool,
     pub proxy_port: Option<u16>,
+    pub max_request_body: Option<u64>,
+    pub max_response_body: Option<u64>,
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub headers: HeadersConfig,
+    pub cors: Option<CorsConfig>,
+    /// SPA fallback document, see `router::serve_static`
+    pub fallback_file: Option<PathBuf>,
+    pub directory_listing: bool,
+    /// Error page files by status, see `error_pages`
+    pub error_pages: BTreeMap<u16, PathBuf>,
+    pub compression: CompressionConfig,
+    pub cache: CacheConfig,
 }
 
 pub struct AppState {
     pub config: ServerConfig,
+    /// Shared by every proxied request so backend connections are reused
+    pub proxy_client: reqwest::Client,
 }
 
 impl AppState {
     pub fn new(config: ServerConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            proxy_client: reqwest::Client::new(),
+        }
     }
 }
 
-// Re-export proxy function
-pub use proxy::proxy_request;
\ No newline at end of file
+/// Fixtures shared by the server tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use axum::Router;
+    use std::path::{Path, PathBuf};
+
+    /// A site root in the temp directory, removed when dropped so a failing
+    /// test doesn't leave it behind.
+    pub struct TempSite {
+        pub root: PathBuf,
+    }
+
+    impl TempSite {
+        pub fn new() -> Self {
+            let root = std::env::temp_dir().join(format!("localhostify-test-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&root).unwrap();
+            Self { root }
+        }
+
+        /// Write a file under the root, creating its folders, and return its path.
+        pub fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> PathBuf {
+            let path = self.root.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).unwrap();
+            }
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempSite {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// Serve `app` on a free local port and return the port.
+    pub async fn spawn_backend(app: Router) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        port
+    }
+}
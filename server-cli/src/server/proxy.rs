@@ -1,137 +1,163 @@
 use axum::{
     body::Body,
-    extract::Request,
-    http::StatusCode,
-    response::Response,
+    extract::{ConnectInfo, Request},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
-use std::sync::Arc;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use std::{net::SocketAddr, sync::Arc};
 use tracing::{error, info, warn};
 
-use super::AppState;
+use super::{
+    error_pages::{mark_generated, GeneratedError},
+    tunnel,
+    upstream::{Upstream, UpstreamGroup},
+    AppState,
+};
+use crate::events::{self, Event};
+
+/// Marks the response to a request whose backend couldn't be connected to.
+#[derive(Clone, Copy)]
+struct BackendUnreachable;
+
+/// Proxy a request to one of the backends of `group`. Connection errors
+/// count against the chosen backend; the request isn't retried on another
+/// one since its body may already be consumed.
+pub async fn proxy_to_group(req: Request, state: Arc<AppState>, group: &Arc<UpstreamGroup>) -> Response {
+    let client = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let backend = group.select(client);
+
+    let response = match proxy_request(req, state, backend.upstream()).await {
+        Ok(response) => response,
+        Err(status) => mark_generated(status.into_response()),
+    };
+    backend.report(response.extensions().get::<BackendUnreachable>().is_none());
 
-/// Proxy an incoming axum Request to a local backend (reqwest) and convert the
-/// response back into an axum Response. We convert header and method types
-/// between hyper/axum and reqwest using string conversions to avoid http-type
-/// mismatches.
+    // The backend stays busy until its response body has been sent
+    let (parts, body) = response.into_parts();
+    let body = body.map_frame(move |frame| {
+        let _ = &backend;
+        frame
+    });
+    Response::from_parts(parts, Body::new(body))
+}
+
+/// Proxy an incoming axum Request to a backend and stream the backend's
+/// response back. Request and response bodies are forwarded chunk
+/// by chunk with backpressure, so large uploads, downloads and Server-Sent
+/// Events never sit in memory. Bodies over the site's configured limits are
+/// rejected with 413 (request) or cut off (response). Upgrade requests such
+/// as WebSockets are tunneled to the backend instead.
 pub async fn proxy_request(
     req: Request,
     state: Arc<AppState>,
+    upstream: &Upstream,
 ) -> Result<Response, StatusCode> {
-    let proxy_port = match state.config.proxy_port {
-        Some(port) => port,
-        None => {
-            error!("Proxy request received but no proxy port configured");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    if tunnel::is_upgrade_request(req.headers()) {
+        return tunnel::proxy_upgrade(req, state, upstream).await;
+    }
 
-    let uri = req.uri().clone();
+    let (parts, body) = req.into_parts();
+    let uri = parts.uri;
     let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or(uri.path());
-    let method_str = req.method().as_str().to_string();
+    let method_str = parts.method.as_str().to_string();
 
     // Build the proxy URL
-    let proxy_url = format!("http://127.0.0.1:{}{}", proxy_port, path_and_query);
+    let proxy_url = upstream.url_for(path_and_query);
     info!("🔄 Proxying {} {} to {}", method_str, uri.path(), proxy_url);
 
-    // Create a reqwest client
-    let client = reqwest::Client::new();
-
-    // Convert incoming headers (hyper) into a reqwest HeaderMap by stringifying
-    // names and values. Skip hop-by-hop headers.
-    let mut reqwest_headers = reqwest::header::HeaderMap::new();
-    for (name, value) in req.headers().iter() {
-        let name_str = name.as_str().to_lowercase();
-        if is_hop_by_hop_header(&name_str) {
-            continue;
-        }
-        if let Ok(val_str) = value.to_str() {
-            if let Ok(hname) = reqwest::header::HeaderName::from_bytes(name.as_str().as_bytes()) {
-                if let Ok(hval) = reqwest::header::HeaderValue::from_str(val_str) {
-                    let _ = reqwest_headers.insert(hname, hval);
-                }
-            }
+    // Reject uploads that announce a body over the limit before contacting the backend
+    if let Some(limit) = state.config.max_request_body {
+        if content_length(&parts.headers).is_some_and(|length| length > limit) {
+            warn!("❌ Request body larger than {} bytes, not proxying", limit);
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
         }
     }
 
-    // Read request body
-    let body_bytes = match axum::body::to_bytes(req.into_body(), usize::MAX).await {
-        Ok(b) => b,
-        Err(e) => {
-            error!("Failed to read request body: {}", e);
-            return Err(StatusCode::BAD_REQUEST);
-        }
-    };
-
-    // Build reqwest method from hyper method string
-    let reqwest_method = match reqwest::Method::from_bytes(method_str.as_bytes()) {
-        Ok(m) => m,
-        Err(_) => reqwest::Method::GET,
-    };
-
-    let proxy_req = client
-        .request(reqwest_method, &proxy_url)
-        .headers(reqwest_headers)
-        .body(body_bytes.to_vec());
+    let proxy_req = state
+        .proxy_client
+        .request(parts.method, &proxy_url)
+        .headers(forwarded_headers(&parts.headers))
+        .body(reqwest::Body::wrap_stream(
+            limit_body(body, state.config.max_request_body).into_data_stream(),
+        ));
 
     match proxy_req.send().await {
         Ok(resp) => {
-            let status_code = resp.status().as_u16();
-            let headers = resp.headers().clone();
-            
             info!("✅ Proxy response: {}", resp.status());
 
-            // Convert response headers back into hyper header types for axum
-            let hyper_status = hyper::StatusCode::from_u16(status_code)
-                .unwrap_or(hyper::StatusCode::INTERNAL_SERVER_ERROR);
-            let mut response_builder = Response::builder().status(hyper_status);
-            
-            let final_body = match resp.bytes().await {
-                Ok(b) => b.to_vec(),
-                Err(e) => {
-                    error!("Failed to read proxy response body: {}", e);
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            if let Some(limit) = state.config.max_response_body {
+                if resp.content_length().is_some_and(|length| length > limit) {
+                    let message = format!("Backend response larger than {} bytes", limit);
+                    warn!("❌ {}", message);
+                    events::emit(Event::ProxyError {
+                        site: &state.config.name,
+                        method: &method_str,
+                        path: uri.path(),
+                        upstream: &proxy_url,
+                        error: &message,
+                    });
+                    return Ok(json_error(StatusCode::BAD_GATEWAY, &message));
                 }
-            };
+            }
 
-            if let Some(headers_map) = response_builder.headers_mut() {
-                for (name, value) in headers.iter() {
-                    // Skip hop-by-hop headers
-                    if is_hop_by_hop_header(&name.as_str().to_lowercase()) {
-                        continue;
-                    }
-                    if let Ok(hname) = hyper::header::HeaderName::from_bytes(name.as_str().as_bytes()) {
-                        if let Ok(hval) = hyper::header::HeaderValue::from_bytes(value.as_bytes()) {
-                            headers_map.insert(hname, hval);
-                        }
-                    }
-                }
+            let resp: axum::http::Response<reqwest::Body> = resp.into();
+            let (backend_parts, backend_body) = resp.into_parts();
+            let mut response_builder = Response::builder().status(backend_parts.status);
 
-                // Add permissive CORS headers for browser compatibility
-                let _ = headers_map.insert(hyper::header::HeaderName::from_static("access-control-allow-origin"), hyper::header::HeaderValue::from_static("*"));
-                let _ = headers_map.insert(hyper::header::HeaderName::from_static("access-control-allow-methods"), hyper::header::HeaderValue::from_static("GET, POST, PUT, DELETE, OPTIONS"));
-                let _ = headers_map.insert(hyper::header::HeaderName::from_static("access-control-allow-headers"), hyper::header::HeaderValue::from_static("content-type, authorization"));
+            // CORS headers are left to the site's CORS layer
+            if let Some(headers_map) = response_builder.headers_mut() {
+                *headers_map = forwarded_headers(&backend_parts.headers);
             }
 
-            let response = response_builder.body(Body::from(final_body)).unwrap();
-            Ok(response)
+            // Errors in the middle of the body can only be reported, the
+            // status line has already been sent by then
+            let site = state.config.name.clone();
+            let path = uri.path().to_string();
+            let backend_body = backend_body.map_err(move |e| {
+                error!("Failed to read proxy response body: {}", e);
+                events::emit(Event::ProxyError {
+                    site: &site,
+                    method: &method_str,
+                    path: &path,
+                    upstream: &proxy_url,
+                    error: &e.to_string(),
+                });
+                e
+            });
+
+            let body = limit_body(Body::new(backend_body), state.config.max_response_body);
+            Ok(response_builder.body(body).unwrap())
+        }
+        Err(e) if is_body_too_large(&e) => {
+            warn!("❌ Request body over the {} byte limit", state.config.max_request_body.unwrap_or_default());
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
         }
         Err(e) => {
             warn!("❌ Proxy request failed: {}", e);
+            events::emit(Event::ProxyError {
+                site: &state.config.name,
+                method: &method_str,
+                path: uri.path(),
+                upstream: &proxy_url,
+                error: &e.to_string(),
+            });
             if e.is_connect() {
-                error!("Backend server not reachable at localhost:{}", proxy_port);
+                error!("Backend server not reachable at {}", upstream);
                 let error_body = format!(
                     r##"{{
     "error": "Backend server not available",
-    "message": "No server found at localhost:{}.",
-    "suggestion": "Start your backend on port {}"
+    "message": "No server found at {}.",
+    "suggestion": "Start your backend at {}"
 }}"##,
-                    proxy_port, proxy_port
+                    upstream, upstream
                 );
 
                 let response = Response::builder()
                     .status(StatusCode::BAD_GATEWAY)
                     .header(hyper::header::CONTENT_TYPE, "application/json")
-                    .header("access-control-allow-origin", "*")
+                    .extension(BackendUnreachable)
+                    .extension(GeneratedError)
                     .body(Body::from(error_body))
                     .unwrap();
 
@@ -143,9 +169,167 @@ pub async fn proxy_request(
     }
 }
 
-fn is_hop_by_hop_header(name: &str) -> bool {
-    match name {
-        "connection" | "keep-alive" | "proxy-authenticate" | "proxy-authorization" | "te" | "trailers" | "transfer-encoding" | "upgrade" | "host" => true,
-        _ => false,
+/// Copy headers between client and backend, keeping repeated headers and
+/// dropping hop-by-hop ones.
+fn forwarded_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut forwarded = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers.iter() {
+        if !is_hop_by_hop_header(name.as_str()) {
+            forwarded.append(name.clone(), value.clone());
+        }
+    }
+    forwarded
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Make a body fail once more than `limit` bytes have gone through it.
+fn limit_body(body: Body, limit: Option<u64>) -> Body {
+    match limit {
+        Some(limit) => Body::new(Limited::new(body, usize::try_from(limit).unwrap_or(usize::MAX))),
+        None => body,
     }
-}
\ No newline at end of file
+}
+
+/// Whether a failed backend request was cut off by the request body limit.
+fn is_body_too_large(e: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .extension(GeneratedError)
+        .body(Body::from(serde_json::json!({ "error": message }).to_string()))
+        .unwrap()
+}
+
+pub(super) fn is_hop_by_hop_header(name: &str) -> bool {
+    matches!(
+        name,
+        "connection" | "keep-alive" | "proxy-authenticate" | "proxy-authorization" | "te" | "trailers" | "transfer-encoding" | "upgrade" | "host"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{testing, ServerConfig};
+    use axum::{body::Bytes, routing::{get, post}, Router};
+    use futures::StreamExt;
+    use std::{convert::Infallible, time::Duration};
+
+    async fn spawn_backend() -> u16 {
+        let app = Router::new()
+            .route("/echo", post(|body: Bytes| async move { body }))
+            .route("/events", get(|| async {
+                let events = futures::stream::unfold(0, |n| async move {
+                    if n == 0 {
+                        Some((Ok::<_, Infallible>(Bytes::from("data: first\n\n")), 1))
+                    } else if n == 1 {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        Some((Ok(Bytes::from("data: second\n\n")), 2))
+                    } else {
+                        None
+                    }
+                });
+                ([(header::CONTENT_TYPE, "text/event-stream")], Body::from_stream(events))
+            }))
+            .route("/large", get(|| async { "x".repeat(4096) }));
+
+        testing::spawn_backend(app).await
+    }
+
+    fn state(proxy_port: u16, max_request_body: Option<u64>, max_response_body: Option<u64>) -> Arc<AppState> {
+        Arc::new(AppState::new(ServerConfig {
+            name: "test".to_string(),
+            root_dir: ".".into(),
+            proxy_port: Some(proxy_port),
+            max_request_body,
+            max_response_body,
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_response_is_streamed() {
+        let port = spawn_backend().await;
+        let state = state(port, None, None);
+        let req = Request::builder().uri("/events").body(Body::empty()).unwrap();
+
+        let response = proxy_request(req, state, &Upstream::local(port)).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        // The first event arrives while the backend is still holding the stream open
+        let mut stream = response.into_body().into_data_stream();
+        let first = tokio::time::timeout(Duration::from_secs(2), stream.next()).await.unwrap();
+        assert_eq!(first.unwrap().unwrap(), "data: first\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_request_body_is_forwarded() {
+        let port = spawn_backend().await;
+        let state = state(port, Some(1024), None);
+        let chunks = futures::stream::iter(["hello ", "world"].map(Ok::<_, Infallible>));
+        let req = Request::builder().method("POST").uri("/echo").body(Body::from_stream(chunks)).unwrap();
+
+        let response = proxy_request(req, state, &Upstream::local(port)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello world");
+    }
+
+    #[tokio::test]
+    async fn test_body_limits() {
+        let port = spawn_backend().await;
+
+        // Announced length over the limit
+        let req = Request::builder()
+            .method("POST")
+            .uri("/echo")
+            .header(header::CONTENT_LENGTH, "2048")
+            .body(Body::from(vec![0u8; 2048]))
+            .unwrap();
+        assert_eq!(proxy_request(req, state(port, Some(1024), None), &Upstream::local(port)).await.unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Streamed body that grows over the limit
+        let chunks = futures::stream::iter((0..4).map(|_| Ok::<_, Infallible>(vec![0u8; 512])));
+        let req = Request::builder().method("POST").uri("/echo").body(Body::from_stream(chunks)).unwrap();
+        assert_eq!(proxy_request(req, state(port, Some(1024), None), &Upstream::local(port)).await.unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::builder().uri("/large").body(Body::empty()).unwrap();
+        let response = proxy_request(req, state(port, None, Some(1024)), &Upstream::local(port)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_group_ejects_unreachable_backend() {
+        let port = spawn_backend().await;
+        let config = crate::config::UpstreamConfig {
+            urls: Some(vec![format!("http://127.0.0.1:{}", port), "http://127.0.0.1:1".to_string()]),
+            max_failures: Some(1),
+            ..Default::default()
+        };
+        let group = Arc::new(UpstreamGroup::new("api", &config).unwrap());
+
+        let mut statuses = Vec::new();
+        for _ in 0..4 {
+            let req = Request::builder().uri("/large").body(Body::empty()).unwrap();
+            statuses.push(proxy_to_group(req, state(port, None, None), &group).await.status());
+        }
+        assert_eq!(statuses.iter().filter(|status| **status == StatusCode::BAD_GATEWAY).count(), 1);
+        assert!(group.status().backends[1].ejected);
+    }
+}
@@ -0,0 +1,113 @@
+//! Send plain HTTP clients of an HTTPS site to its HTTPS URL, either from a
+//! companion listener on another port or from the HTTPS port itself.
+
+use axum::{
+    extract::Request,
+    http::{header, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use std::time::Duration;
+use tokio::{
+    net::TcpListener,
+    task::{JoinHandle, JoinSet},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use super::{
+    listener::{accept_connections, drain_connections, ListenerProtocol},
+    vhost::request_host,
+};
+
+/// A router answering every request with a redirect to the same host and
+/// path on `https_port`.
+pub fn redirect_to_https(https_port: u16) -> Router {
+    Router::new().fallback(move |req: Request| async move { redirect(&req, https_port) })
+}
+
+fn redirect(req: &Request, https_port: u16) -> Response {
+    let host = match request_host(req) {
+        Some(host) if host.contains(':') => format!("[{}]", host),
+        Some(host) => host,
+        None => "localhost".to_string(),
+    };
+    let authority = match https_port {
+        443 => host,
+        port => format!("{}:{}", host, port),
+    };
+    let path = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+
+    // Browsers remember a 301; a 308 also keeps the method and body, so
+    // form posts and API calls aren't turned into GETs
+    let status = if matches!(*req.method(), Method::GET | Method::HEAD) {
+        StatusCode::MOVED_PERMANENTLY
+    } else {
+        StatusCode::PERMANENT_REDIRECT
+    };
+    (status, [(header::LOCATION, format!("https://{}{}", authority, path))]).into_response()
+}
+
+/// Serve redirects to `https_port` on `listener` until `shutdown`.
+pub fn spawn_redirect_listener(
+    listener: TcpListener,
+    https_port: u16,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Some(connections) = accept_redirects(listener, https_port, shutdown).await {
+            drain_connections(connections, drain_timeout).await;
+        }
+    })
+}
+
+/// Accept redirects to `https_port` on `listener` until `shutdown`, returning
+/// the connections still open once the listener is closed.
+pub async fn accept_redirects(listener: TcpListener, https_port: u16, shutdown: CancellationToken) -> Option<JoinSet<()>> {
+    if let Ok(addr) = listener.local_addr() {
+        info!("↪️  Redirecting http://{} to HTTPS port {}", addr, https_port);
+    }
+    let app = redirect_to_https(https_port);
+    match accept_connections(listener, app, ListenerProtocol::default(), shutdown).await {
+        Ok(connections) => Some(connections),
+        Err(e) => {
+            error!("Redirect listener for port {} failed: {}", https_port, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::util::ServiceExt;
+
+    async fn location(method: Method, uri: &str, host: Option<&str>, https_port: u16) -> (StatusCode, String) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(host) = host {
+            req = req.header(header::HOST, host);
+        }
+        let response = redirect_to_https(https_port)
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let location = response.headers()[header::LOCATION].to_str().unwrap().to_string();
+        (response.status(), location)
+    }
+
+    #[tokio::test]
+    async fn test_redirects_to_https() {
+        assert_eq!(
+            location(Method::GET, "/docs?page=2", Some("App.Local:8080"), 8443).await,
+            (StatusCode::MOVED_PERMANENTLY, "https://app.local:8443/docs?page=2".to_string())
+        );
+        assert_eq!(
+            location(Method::POST, "/api/login", Some("app.local"), 443).await,
+            (StatusCode::PERMANENT_REDIRECT, "https://app.local/api/login".to_string())
+        );
+        assert_eq!(location(Method::GET, "/", Some("[::1]:80"), 8443).await.1, "https://[::1]:8443/");
+        assert_eq!(location(Method::HEAD, "/", None, 8443).await.1, "https://localhost:8443/");
+    }
+}
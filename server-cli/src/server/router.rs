@@ -0,0 +1,408 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use std::{path::Path, sync::Arc, time::Instant};
+use tower::util::ServiceExt;
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    trace::TraceLayer,
+};
+use tracing::warn;
+
+use super::{
+    cache::{apply_cache_policy, mark_static, prefer_etag, CachePolicy},
+    compression::{compression_layer, serve_precompressed},
+    cors::cors_layer,
+    error_pages::{apply_error_pages, ErrorPages},
+    headers::{apply_header_policy, HeaderPolicy},
+    listing::list_directory,
+    proxy_to_group,
+    routes::Routes,
+    tunnel::is_upgrade_request,
+    upstream::{compile_upstreams, Upstream, UpstreamGroup},
+    AppState,
+};
+use crate::events::{self, Event};
+
+pub async fn build_router(state: Arc<AppState>) -> Result<Router, Box<dyn std::error::Error + Send + Sync>> {
+    let upstreams = compile_upstreams(&state.config.upstreams)?;
+    let default_upstream = state
+        .config
+        .proxy_port
+        .map(|port| Arc::new(UpstreamGroup::single(Upstream::local(port))));
+    for group in upstreams.values() {
+        group.start_health_checks(state.proxy_client.clone());
+    }
+
+    let groups: Vec<Arc<UpstreamGroup>> = upstreams.values().cloned().collect();
+    let mut router = Router::new()
+        .route("/health", get(health_check))
+        .route("/healthz", get(health_check))
+        .route("/health/upstreams", get(move |state| health_report(state, groups)))
+        .with_state(state.clone())
+        .layer(TraceLayer::new_for_http());
+
+    // Add static file serving
+    let serve_dir = ServeDir::new(&state.config.root_dir)
+        .append_index_html_on_directories(true);
+    let serve_dir = serve_precompressed(serve_dir, &state.config.compression);
+    let fallback_file = state.config.fallback_file.clone();
+    let listing_root = state.config.directory_listing.then(|| state.config.root_dir.clone());
+    if let Some(file) = &fallback_file {
+        if !file.is_file() {
+            warn!("⚠️  SPA fallback {} does not exist yet", file.display());
+        }
+    }
+
+    // Everything else goes through the site's routing rules, then the
+    // built-in proxy paths, then the static files (see `routes` for the
+    // details). A router has a single fallback, so they share one handler.
+    let routes = Arc::new(Routes::compile(&state.config.routes, &upstreams)?);
+    let fallback_state = state.clone();
+    router = router.fallback(move |req: Request| async move {
+        if let Some(rule) = routes.find(req.uri().path()) {
+            return rule.serve(req, fallback_state).await;
+        }
+
+        match &default_upstream {
+            // Static files can't answer upgrades, so WebSocket connections
+            // such as dev server HMR always go to the backend
+            Some(upstream) if should_proxy(req.uri()) || is_upgrade_request(req.headers()) => {
+                proxy_to_group(req, fallback_state, upstream).await
+            }
+            _ => serve_static(&serve_dir, listing_root.as_deref(), fallback_file.as_deref(), req).await,
+        }
+    });
+
+    // Layers added after the fallback cover the whole site, files and
+    // proxied responses included
+    let error_pages = ErrorPages::new(&state.config.error_pages);
+    if !error_pages.is_empty() {
+        router = router.layer(middleware::from_fn_with_state(Arc::new(error_pages), apply_error_pages));
+    }
+    if let Some(compression) = compression_layer(&state.config.compression)? {
+        router = router.layer(compression);
+    }
+    // Outside compression, so ETags know the encoding the client gets
+    let cache = CachePolicy::compile(&state.config.cache)?;
+    router = router.layer(middleware::from_fn_with_state(Arc::new(cache), apply_cache_policy));
+    if let Some(cors) = cors_layer(state.config.cors.as_ref())? {
+        router = router.layer(cors);
+    }
+
+    let headers = HeaderPolicy::compile(&state.config.headers, state.config.https_enabled)?;
+    if !headers.is_empty() {
+        router = router.layer(middleware::from_fn_with_state(Arc::new(headers), apply_header_policy));
+    }
+
+    if events::json_enabled() {
+        router = router.layer(middleware::from_fn_with_state(state, emit_request_event));
+    }
+
+    Ok(router)
+}
+
+/// Emit a `request` event summarizing every request served by the site.
+async fn emit_request_event(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let started = Instant::now();
+
+    let response = next.run(req).await;
+
+    events::emit(Event::Request {
+        site: &state.config.name,
+        method: method.as_str(),
+        path: &path,
+        status: response.status().as_u16(),
+        duration_ms: started.elapsed().as_millis() as u64,
+    });
+    response
+}
+
+/// Serve a file from the site root. Folders without an `index.html` are
+/// listed when `listing_root` is set. With a SPA fallback, page requests
+/// for paths that aren't files get the fallback document instead of a 404,
+/// so the app's client-side router can handle them.
+async fn serve_static(
+    serve_dir: &ServeDir,
+    listing_root: Option<&Path>,
+    fallback_file: Option<&Path>,
+    mut req: Request,
+) -> Response {
+    prefer_etag(&mut req);
+    let path = req.uri().path().to_string();
+    // ServeDir redirects folders to their path with a trailing slash first
+    let listing = listing_root
+        .filter(|_| path.ends_with('/') && matches!(*req.method(), Method::GET | Method::HEAD))
+        .map(|root| (root, req.uri().clone(), req.headers().clone()));
+    let fallback = fallback_file.filter(|_| is_page_request(&req)).map(|file| {
+        let mut fallback_req = Request::new(Body::empty());
+        *fallback_req.method_mut() = req.method().clone();
+        *fallback_req.headers_mut() = req.headers().clone();
+        (file, fallback_req)
+    });
+
+    let response = serve_dir.clone().oneshot(req).await.into_response();
+    if response.status() != StatusCode::NOT_FOUND {
+        return mark_static(response, &path);
+    }
+    if let Some((root, uri, headers)) = listing {
+        if let Some(listing) = list_directory(root, &uri, &headers).await {
+            return listing;
+        }
+    }
+    match fallback {
+        Some((file, fallback_req)) => {
+            let response = ServeFile::new(file).oneshot(fallback_req).await.into_response();
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            mark_static(response, &format!("/{}", name))
+        }
+        _ => mark_static(response, &path),
+    }
+}
+
+/// A browser navigating to a page: a GET or HEAD that accepts HTML, for a
+/// path whose last segment has no file extension. Missing assets and API
+/// calls made with `fetch` keep their 404.
+fn is_page_request(req: &Request) -> bool {
+    let accepts_html = req
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|accept| accept.contains("text/html"));
+    let last_segment = req.uri().path().rsplit('/').next().unwrap_or_default();
+    matches!(*req.method(), Method::GET | Method::HEAD) && accepts_html && !last_segment.contains('.')
+}
+
+/// Extensions that are always served from disk, even below an API path
+const STATIC_EXTENSIONS: &[&str] = &[
+    ".html", ".css", ".js", ".png", ".jpg", ".jpeg", ".gif", ".svg", ".ico", ".woff", ".woff2", ".ttf", ".eot",
+];
+
+/// Built-in proxy rule for paths that no routing rule matched.
+fn should_proxy(uri: &Uri) -> bool {
+    let path = uri.path();
+    // Proxy requests that look like API calls
+    let looks_like_api = path.starts_with("/api")
+        || path.starts_with("/v1")
+        || path.starts_with("/graphql")
+        || path.contains("/api/");
+    // But don't proxy requests for static assets
+    looks_like_api && !STATIC_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+/// Site health with the state of every named upstream's backends. The site
+/// is degraded while an upstream has no backend in rotation.
+async fn health_report(State(state): State<Arc<AppState>>, upstreams: Vec<Arc<UpstreamGroup>>) -> Json<serde_json::Value> {
+    let degraded = upstreams.iter().any(|group| !group.is_available());
+    Json(serde_json::json!({
+        "status": if degraded { "degraded" } else { "ok" },
+        "site": state.config.name,
+        "upstreams": upstreams.iter().map(|group| group.status()).collect::<Vec<_>>(),
+    }))
+}
+
+async fn health_check() -> &'static str {
+    "LocalHostify server is healthy"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_proxy() {
+        assert!(should_proxy(&Uri::from_static("/api/users")));
+        assert!(should_proxy(&Uri::from_static("/graphql")));
+        assert!(!should_proxy(&Uri::from_static("/index.html")));
+        // Static assets stay on disk whichever API prefix they are under
+        assert!(!should_proxy(&Uri::from_static("/api/docs/index.html")));
+        assert!(!should_proxy(&Uri::from_static("/v1/app.js")));
+        assert!(!should_proxy(&Uri::from_static("/graphql/logo.svg")));
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        use axum::body::Body;
+
+        let router = build_router(Arc::new(AppState::new(Default::default()))).await.unwrap();
+        let get = |uri: &'static str| router.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap());
+
+        let body = axum::body::to_bytes(get("/health").await.unwrap().into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"LocalHostify server is healthy");
+
+        let body = axum::body::to_bytes(get("/health/upstreams").await.unwrap().into_body(), usize::MAX).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["status"], "ok");
+        assert_eq!(report["upstreams"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_etags_tell_encodings_apart() {
+        use crate::server::{testing::TempSite, ServerConfig};
+        use axum::{body::Body, http::HeaderValue};
+
+        let site = TempSite::new();
+        site.write("app.js", "boot();".repeat(500));
+        let router = build_router(Arc::new(AppState::new(ServerConfig { root_dir: site.root.clone(), ..Default::default() })))
+            .await
+            .unwrap();
+        let get = |encoding: &str, if_none_match: Option<&HeaderValue>| {
+            let mut req = Request::builder().uri("/app.js").header(header::ACCEPT_ENCODING, encoding);
+            if let Some(etag) = if_none_match {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            router.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        let identity = get("identity", None).await.unwrap();
+        let gzip = get("gzip", None).await.unwrap();
+        assert_eq!(gzip.headers()[header::CONTENT_ENCODING], "gzip");
+        let gzip_etag = gzip.headers()[header::ETAG].clone();
+        assert_ne!(gzip_etag, identity.headers()[header::ETAG]);
+        assert!(gzip_etag.to_str().unwrap().ends_with("-gzip\""));
+
+        assert_eq!(get("gzip", Some(&gzip_etag)).await.unwrap().status(), StatusCode::NOT_MODIFIED);
+        // A cached gzip body is no good to a client that can't decode it
+        assert_eq!(get("identity", Some(&gzip_etag)).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_header_policy_covers_files_and_proxied_responses() {
+        use crate::{
+            config::HeadersConfig,
+            server::{
+                testing::{spawn_backend, TempSite},
+                ServerConfig,
+            },
+        };
+        use axum::{body::Body, http::HeaderValue};
+
+        let backend = Router::new().route(
+            "/api/users",
+            get(|| async { ([("x-powered-by", "Express"), ("x-frame-options", "ALLOWALL")], "[]") }),
+        );
+        let backend_port = spawn_backend(backend).await;
+
+        let site = TempSite::new();
+        site.write("index.html", "<h1>hi</h1>");
+
+        let state = Arc::new(AppState::new(ServerConfig {
+            name: "test".to_string(),
+            root_dir: site.root.clone(),
+            proxy_port: Some(backend_port),
+            headers: HeadersConfig {
+                x_frame_options: Some("DENY".to_string()),
+                remove: Some(vec!["x-powered-by".to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let router = build_router(state).await.unwrap();
+
+        for path in ["/index.html", "/api/users"] {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(response.status(), 200, "{}", path);
+            assert_eq!(response.headers().get("x-frame-options"), Some(&HeaderValue::from_static("DENY")));
+            assert!(response.headers().get("x-powered-by").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cors_passthrough_keeps_backend_headers() {
+        use crate::{
+            config::CorsConfig,
+            server::{testing::spawn_backend, ServerConfig},
+        };
+        use axum::{body::Body, http::header};
+
+        let backend = Router::new().route(
+            "/api/me",
+            get(|| async {
+                (
+                    [
+                        (header::ACCESS_CONTROL_ALLOW_ORIGIN, "http://app.local"),
+                        (header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"),
+                    ],
+                    "{}",
+                )
+            }),
+        );
+        let backend_port = spawn_backend(backend).await;
+
+        let site = |cors| ServerConfig {
+            name: "test".to_string(),
+            root_dir: std::env::temp_dir(),
+            proxy_port: Some(backend_port),
+            cors,
+            ..Default::default()
+        };
+        let request = || {
+            Request::builder()
+                .uri("/api/me")
+                .header(header::ORIGIN, "http://app.local")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let passthrough = CorsConfig { passthrough: Some(true), ..Default::default() };
+        let router = build_router(Arc::new(AppState::new(site(Some(passthrough))))).await.unwrap();
+        let response = router.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://app.local");
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        // Without a cors section the site answers for any origin itself
+        let router = build_router(Arc::new(AppState::new(site(None)))).await.unwrap();
+        let response = router.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn test_spa_fallback_serves_pages_only() {
+        use crate::server::{
+            testing::{spawn_backend, TempSite},
+            ServerConfig,
+        };
+
+        let backend_port = spawn_backend(Router::new()).await;
+
+        let site = TempSite::new();
+        let index = site.write("index.html", "<div id=app></div>");
+        site.write("assets/app.js", "boot()");
+
+        let state = Arc::new(AppState::new(ServerConfig {
+            name: "test".to_string(),
+            root_dir: site.root.clone(),
+            proxy_port: Some(backend_port),
+            fallback_file: Some(index),
+            ..Default::default()
+        }));
+        let router = build_router(state).await.unwrap();
+
+        let get = |path: &str, accept: &str| {
+            let req = Request::builder().uri(path).header(header::ACCEPT, accept).body(Body::empty()).unwrap();
+            router.clone().oneshot(req)
+        };
+        let html = "text/html,application/xhtml+xml,*/*;q=0.8";
+
+        let response = get("/dashboard/settings", html).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"<div id=app></div>");
+
+        assert_eq!(get("/assets/app.js", "*/*").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get("/assets/missing.js", html).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(get("/dashboard", "application/json").await.unwrap().status(), StatusCode::NOT_FOUND);
+        // Proxied paths keep the backend's answer
+        assert_eq!(get("/api/missing", html).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,434 @@
+//! Per-site routing rules from `[[sites.routes]]` or `--site ...:route=`.
+//!
+//! How a request that isn't handled by a built-in route (`/health`,
+//! `/healthz` or `/health/upstreams`) is dispatched:
+//!
+//! 1. Rules are tried in the order they are written against the request path
+//!    (without the query string). The first rule that matches decides, later
+//!    rules are not looked at.
+//!    - `path` is a prefix matched on whole segments: `/api` matches `/api`
+//!      and `/api/users` but not `/apis`. `/` matches every path.
+//!    - `glob` must match the whole path. `*` and `?` stay within one
+//!      segment, `**` spans any number of segments, `{a,b}` and `[abc]` work
+//!      as usual.
+//!    - `regex` matches if the expression is found anywhere in the path; use
+//!      `^` and `$` to match the whole path.
+//! 2. The matching rule's target handles the request:
+//!    - `root` serves files from a directory. With a `path` rule the prefix
+//!      is removed first, so `/docs/intro.html` with `path = "/docs"` is read
+//!      from `<root>/intro.html`. Glob and regex rules use the full path.
+//!    - `proxy` forwards the request to a local port or a named upstream,
+//!      full path included. `strip_prefix` removes a `path` rule's prefix
+//!      first, and `rewrite` replaces the prefix (`path` rules) or the
+//!      matched text (`regex` rules), so `path = "/auth"` with
+//!      `rewrite = "/v2"` sends `/auth/login` as `/v2/login`. An upstream
+//!      with several `urls` picks a backend per request (see `upstream`).
+//!    - `redirect` answers with the given status (302 unless set) and the
+//!      target as `Location`. `$1` or `$name` in the target expand to regex
+//!      captures.
+//! 3. When no rule matches and the site has `proxy_to`, API-looking paths
+//!    and WebSocket upgrades go to that backend. Everything else is served
+//!    from the site root.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use std::{collections::BTreeMap, sync::Arc};
+use tower::util::ServiceExt;
+use tower_http::services::ServeDir;
+
+use super::{
+    cache::{mark_static, prefer_etag},
+    proxy::proxy_to_group,
+    upstream::{Upstream, UpstreamGroup},
+    AppState,
+};
+use crate::config::{validate_directory, ProxyTarget, RouteConfig};
+
+/// The compiled routing rules of a site.
+#[derive(Default)]
+pub struct Routes {
+    rules: Vec<RouteRule>,
+}
+
+pub struct RouteRule {
+    matcher: Matcher,
+    target: Target,
+}
+
+enum Matcher {
+    Prefix(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+enum Target {
+    Root(ServeDir),
+    Proxy { upstream: Arc<UpstreamGroup>, rewrite: Rewrite },
+    Redirect { to: String, status: StatusCode },
+}
+
+/// How the path of a proxied request is changed before it's sent.
+enum Rewrite {
+    None,
+    StripPrefix,
+    Replace(String),
+}
+
+impl Routes {
+    /// Check and compile rules. Every rule needs exactly one of `path`,
+    /// `glob` or `regex` and exactly one of `root`, `proxy` or `redirect`.
+    /// Named proxy targets must be in `upstreams`.
+    pub fn compile(routes: &[RouteConfig], upstreams: &BTreeMap<String, Arc<UpstreamGroup>>) -> Result<Self, String> {
+        let rules = routes
+            .iter()
+            .enumerate()
+            .map(|(index, route)| {
+                RouteRule::compile(route, upstreams).map_err(|e| format!("Route {}: {}", index + 1, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The first rule matching `path`.
+    pub fn find(&self, path: &str) -> Option<&RouteRule> {
+        self.rules.iter().find(|rule| rule.matches(path))
+    }
+}
+
+impl RouteRule {
+    fn compile(route: &RouteConfig, upstreams: &BTreeMap<String, Arc<UpstreamGroup>>) -> Result<Self, String> {
+        let matcher = match (&route.path, &route.glob, &route.regex) {
+            (Some(path), None, None) => {
+                if !path.starts_with('/') {
+                    return Err(format!("path {} must start with /", path));
+                }
+                Matcher::Prefix(path.trim_end_matches('/').to_string())
+            }
+            (None, Some(glob), None) => {
+                let glob = GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| format!("invalid glob {}: {}", glob, e))?;
+                Matcher::Glob(glob.compile_matcher())
+            }
+            (None, None, Some(regex)) => {
+                Matcher::Regex(Regex::new(regex).map_err(|e| format!("invalid regex {}: {}", regex, e))?)
+            }
+            _ => return Err("needs exactly one of path, glob or regex".to_string()),
+        };
+
+        if route.status.is_some() && route.redirect.is_none() {
+            return Err("status only applies to redirects".to_string());
+        }
+        if (route.strip_prefix.is_some() || route.rewrite.is_some()) && route.proxy.is_none() {
+            return Err("strip_prefix and rewrite only apply to proxies".to_string());
+        }
+
+        let target = match (&route.root, &route.proxy, &route.redirect) {
+            (Some(root), None, None) => {
+                validate_directory(root).map_err(|e| e.to_string())?;
+                Target::Root(ServeDir::new(root).append_index_html_on_directories(true))
+            }
+            (None, Some(proxy), None) => {
+                let upstream = match proxy {
+                    ProxyTarget::Port(port) => Arc::new(UpstreamGroup::single(Upstream::local(*port))),
+                    ProxyTarget::Upstream(name) => upstreams
+                        .get(name)
+                        .cloned()
+                        .ok_or_else(|| format!("unknown upstream {}", name))?,
+                };
+                let rewrite = match (route.strip_prefix.unwrap_or(false), &route.rewrite, &matcher) {
+                    (false, None, _) => Rewrite::None,
+                    (true, None, Matcher::Prefix(_)) => Rewrite::StripPrefix,
+                    (false, Some(to), Matcher::Prefix(_) | Matcher::Regex(_)) => Rewrite::Replace(to.clone()),
+                    (true, Some(_), _) => return Err("use either strip_prefix or rewrite".to_string()),
+                    (true, None, _) => return Err("strip_prefix needs a path rule".to_string()),
+                    (false, Some(_), _) => return Err("rewrite needs a path or regex rule".to_string()),
+                };
+                Target::Proxy { upstream, rewrite }
+            }
+            (None, None, Some(to)) => {
+                let status = StatusCode::from_u16(route.status.unwrap_or(302))
+                    .ok()
+                    .filter(StatusCode::is_redirection)
+                    .ok_or_else(|| format!("invalid redirect status {}", route.status.unwrap_or_default()))?;
+                Target::Redirect { to: to.clone(), status }
+            }
+            _ => return Err("needs exactly one of root, proxy or redirect".to_string()),
+        };
+
+        Ok(Self { matcher, target })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match &self.matcher {
+            // An empty prefix is what's left of "/"
+            Matcher::Prefix(prefix) => {
+                path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }
+            Matcher::Glob(glob) => glob.is_match(path),
+            Matcher::Regex(regex) => regex.is_match(path),
+        }
+    }
+
+    pub async fn serve(&self, req: Request, state: Arc<AppState>) -> Response {
+        match &self.target {
+            Target::Root(serve_dir) => {
+                let path = req.uri().path().to_string();
+                let prefix = match &self.matcher {
+                    Matcher::Prefix(prefix) => prefix.as_str(),
+                    _ => "",
+                };
+                // `/docs` is the root directory itself, relative links on its
+                // index page only work from `/docs/`
+                if !prefix.is_empty() && path == prefix {
+                    return append_slash(req.uri());
+                }
+
+                let mut req = if prefix.is_empty() { req } else { strip_prefix(req, prefix, "") };
+                prefer_etag(&mut req);
+                let mut response = serve_dir.clone().oneshot(req).await.into_response();
+                // ServeDir redirects folders to the path it saw, without the prefix
+                if response.status().is_redirection() {
+                    restore_prefix(&mut response, prefix);
+                }
+                mark_static(response, &path)
+            }
+            Target::Proxy { upstream, rewrite } => {
+                let req = self.rewrite(req, rewrite);
+                proxy_to_group(req, state, upstream).await
+            }
+            Target::Redirect { to, status } => {
+                let location = self.redirect_location(req.uri().path(), to);
+                Response::builder()
+                    .status(*status)
+                    .header(header::LOCATION, location)
+                    .body(Body::empty())
+                    .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+        }
+    }
+
+    fn rewrite(&self, req: Request, rewrite: &Rewrite) -> Request {
+        match (rewrite, &self.matcher) {
+            (Rewrite::StripPrefix, Matcher::Prefix(prefix)) => strip_prefix(req, prefix, ""),
+            (Rewrite::Replace(to), Matcher::Prefix(prefix)) => strip_prefix(req, prefix, to.trim_end_matches('/')),
+            (Rewrite::Replace(to), Matcher::Regex(regex)) => {
+                let path = regex.replace(req.uri().path(), to.as_str()).into_owned();
+                set_path(req, &path)
+            }
+            _ => req,
+        }
+    }
+
+    fn redirect_location(&self, path: &str, to: &str) -> String {
+        match &self.matcher {
+            Matcher::Regex(regex) => match regex.captures(path) {
+                Some(captures) => {
+                    let mut location = String::new();
+                    captures.expand(to, &mut location);
+                    location
+                }
+                None => to.to_string(),
+            },
+            _ => to.to_string(),
+        }
+    }
+}
+
+/// Replace `prefix` at the start of the request path with `replacement`.
+fn strip_prefix(req: Request, prefix: &str, replacement: &str) -> Request {
+    let path = req.uri().path();
+    let rest = path.strip_prefix(prefix).unwrap_or(path);
+    let path = format!("{}{}", replacement, rest);
+    set_path(req, &path)
+}
+
+/// Redirect a directory to its path with a trailing slash, like `ServeDir`.
+fn append_slash(uri: &Uri) -> Response {
+    let location = match uri.query() {
+        Some(query) => format!("{}/?{}", uri.path(), query),
+        None => format!("{}/", uri.path()),
+    };
+    (StatusCode::TEMPORARY_REDIRECT, [(header::LOCATION, location)]).into_response()
+}
+
+/// Put a stripped `prefix` back in front of a local redirect's `Location`.
+fn restore_prefix(response: &mut Response, prefix: &str) {
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|location| !prefix.is_empty() && location.starts_with('/'));
+    if let Some(location) = location.and_then(|location| HeaderValue::try_from(format!("{}{}", prefix, location)).ok()) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+}
+
+/// Change the request path, keeping the query.
+fn set_path(mut req: Request, path: &str) -> Request {
+    let path = if path.is_empty() { "/" } else { path };
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+
+    if let Ok(uri) = path_and_query.parse::<Uri>() {
+        *req.uri_mut() = uri;
+    }
+    req
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::TempSite;
+
+    fn route(matcher: &str, value: &str) -> RouteConfig {
+        let mut route = RouteConfig { proxy: Some(ProxyTarget::Port(3000)), ..Default::default() };
+        match matcher {
+            "path" => route.path = Some(value.to_string()),
+            "glob" => route.glob = Some(value.to_string()),
+            _ => route.regex = Some(value.to_string()),
+        }
+        route
+    }
+
+    fn compile(routes: &[RouteConfig]) -> Result<Routes, String> {
+        let upstream = UpstreamGroup::single(Upstream::parse("auth", "http://10.0.0.5:4000").unwrap());
+        let upstreams = BTreeMap::from([("auth".to_string(), Arc::new(upstream))]);
+        Routes::compile(routes, &upstreams)
+    }
+
+    fn matches(route: RouteConfig, path: &str) -> bool {
+        compile(&[route]).unwrap().find(path).is_some()
+    }
+
+    fn rewritten(route: RouteConfig, uri: &str) -> String {
+        let routes = compile(&[route]).unwrap();
+        let rule = routes.find(uri.split('?').next().unwrap()).unwrap();
+        let Target::Proxy { rewrite, .. } = &rule.target else { panic!("not a proxy rule") };
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        rule.rewrite(req, rewrite).uri().to_string()
+    }
+
+    #[test]
+    fn test_prefix_matches_whole_segments() {
+        assert!(matches(route("path", "/api"), "/api"));
+        assert!(matches(route("path", "/api/"), "/api/users"));
+        assert!(!matches(route("path", "/api"), "/apis"));
+        assert!(matches(route("path", "/"), "/anything/at/all"));
+    }
+
+    #[test]
+    fn test_glob_and_regex() {
+        assert!(matches(route("glob", "/assets/*.js"), "/assets/app.js"));
+        assert!(!matches(route("glob", "/assets/*.js"), "/assets/lib/app.js"));
+        assert!(matches(route("glob", "/assets/**/*.{js,css}"), "/assets/lib/app.css"));
+        assert!(matches(route("regex", r"^/v\d+/"), "/v2/users"));
+        assert!(!matches(route("regex", r"^/v\d+/"), "/api/v2/users"));
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let mut redirect = route("path", "/api/old");
+        redirect.proxy = None;
+        redirect.redirect = Some("/api/new".to_string());
+        let routes = compile(&[redirect, route("path", "/api")]).unwrap();
+
+        assert!(matches!(routes.find("/api/old/x").unwrap().target, Target::Redirect { .. }));
+        assert!(matches!(routes.find("/api/other").unwrap().target, Target::Proxy { .. }));
+        assert!(routes.find("/index.html").is_none());
+    }
+
+    #[test]
+    fn test_redirect_expands_captures() {
+        let mut redirect = route("regex", r"^/blog/(?P<slug>[^/]+)$");
+        redirect.proxy = None;
+        redirect.redirect = Some("/posts/$slug".to_string());
+        redirect.status = Some(301);
+        let routes = compile(&[redirect]).unwrap();
+
+        let rule = routes.find("/blog/hello").unwrap();
+        assert_eq!(rule.redirect_location("/blog/hello", "/posts/$slug"), "/posts/hello");
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        assert!(compile(&[RouteConfig { proxy: Some(ProxyTarget::Port(3000)), ..Default::default() }]).is_err());
+        assert!(compile(&[RouteConfig { path: Some("/".into()), ..Default::default() }]).is_err());
+        assert!(compile(&[route("regex", "(")]).is_err());
+        assert!(compile(&[route("path", "api")]).is_err());
+
+        let mut unknown = route("path", "/api");
+        unknown.proxy = Some(ProxyTarget::Upstream("billing".to_string()));
+        assert!(compile(&[unknown]).is_err());
+
+        let mut glob_strip = route("glob", "/api/*");
+        glob_strip.strip_prefix = Some(true);
+        assert!(compile(&[glob_strip]).is_err());
+
+        let mut bad_status = route("path", "/old");
+        bad_status.proxy = None;
+        bad_status.redirect = Some("/new".to_string());
+        bad_status.status = Some(200);
+        assert!(compile(&[bad_status]).is_err());
+    }
+
+    #[test]
+    fn test_proxy_path_rewrites() {
+        let mut strip = route("path", "/api");
+        strip.strip_prefix = Some(true);
+        assert_eq!(rewritten(strip.clone(), "/api/users?page=2"), "/users?page=2");
+        assert_eq!(rewritten(strip, "/api"), "/");
+
+        let mut replace = route("path", "/auth");
+        replace.proxy = Some(ProxyTarget::Upstream("auth".to_string()));
+        replace.rewrite = Some("/v2/".to_string());
+        assert_eq!(rewritten(replace, "/auth/login"), "/v2/login");
+
+        let mut regex = route("regex", r"^/ws/(\w+)");
+        regex.rewrite = Some("/socket/$1".to_string());
+        assert_eq!(rewritten(regex, "/ws/chat/room"), "/socket/chat/room");
+    }
+
+    #[tokio::test]
+    async fn test_directories_under_a_prefix_redirect_with_it() {
+        let site = TempSite::new();
+        site.write("index.html", "docs");
+        site.write("guide/index.html", "guide");
+
+        let docs = RouteConfig { path: Some("/docs".to_string()), root: Some(site.root.clone()), ..Default::default() };
+        let routes = compile(&[docs]).unwrap();
+        let state = Arc::new(AppState::new(Default::default()));
+        let get = |uri: &str| {
+            let rule = routes.find(uri.split('?').next().unwrap()).unwrap();
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            rule.serve(req, state.clone())
+        };
+
+        for (uri, location) in [("/docs", "/docs/"), ("/docs/guide", "/docs/guide/"), ("/docs/guide?v=1", "/docs/guide/?v=1")] {
+            let response = get(uri).await;
+            assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT, "{}", uri);
+            assert_eq!(response.headers()[header::LOCATION], location);
+        }
+        for uri in ["/docs/", "/docs/guide/"] {
+            assert_eq!(get(uri).await.status(), StatusCode::OK, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn test_strip_prefix_keeps_query() {
+        let req = Request::builder().uri("/docs/intro.html?v=1").body(Body::empty()).unwrap();
+        assert_eq!(strip_prefix(req, "/docs", "").uri(), "/intro.html?v=1");
+
+        let req = Request::builder().uri("/docs").body(Body::empty()).unwrap();
+        assert_eq!(strip_prefix(req, "/docs", "").uri(), "/");
+    }
+}
@@ -0,0 +1,40 @@
+use tracing::info;
+
+/// Resolve when the process is asked to stop: Ctrl+C everywhere, SIGTERM on
+/// Unix and closing the console window on Windows.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(windows)]
+    let terminate = async {
+        match tokio::signal::windows::ctrl_close() {
+            Ok(mut ctrl_close) => {
+                ctrl_close.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(any(unix, windows)))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("🛑 Received Ctrl+C, shutting down..."),
+        _ = terminate => info!("🛑 Received termination signal, shutting down..."),
+    }
+}
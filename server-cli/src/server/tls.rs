@@ -0,0 +1,399 @@
+//! TLS for HTTPS listeners: picking the certificate of the site a client
+//! asks for by server name (SNI), loading it and swapping it when a site's
+//! certificate files change on disk.
+
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use std::{
+    error::Error,
+    fmt,
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{
+    ssl::{CertFiles, CertStore, SiteCert, TlsConfig},
+    vhost::VirtualHosts,
+    watch::{watch_file, POLL_INTERVAL},
+};
+
+/// The current certificate of one site, replaced when its files change.
+#[derive(Debug)]
+struct CertSlot {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertSlot {
+    fn new(key: CertifiedKey) -> Self {
+        Self { current: RwLock::new(Arc::new(key)) }
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn replace(&self, key: CertifiedKey) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+    }
+}
+
+/// Hands every handshake the certificate of the site named in the client
+/// hello, with the same matching as requests (exact names, then `*.`
+/// wildcards). Unknown names and clients that send none, such as ones
+/// connecting by IP address, get the default site's certificate.
+struct SniResolver {
+    hosts: VirtualHosts<Arc<CertSlot>>,
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniResolver").finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().map(str::to_ascii_lowercase);
+        self.hosts.resolve(name.as_deref()).map(|slot| slot.current())
+    }
+}
+
+/// Build the TLS acceptor of a listener. A site's own certificate files are
+/// watched until `shutdown` and reloaded when they change; if the new files
+/// are invalid the previous certificate stays in use.
+pub async fn create_tls_acceptor(tls: &TlsConfig, shutdown: &CancellationToken) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let mut hosts = VirtualHosts::new();
+    for (index, cert) in tls.certs.iter().enumerate() {
+        let slot = Arc::new(CertSlot::new(load_site_cert(cert).await?));
+        if let Some(files) = &cert.files {
+            tokio::spawn(reload_on_change(files.clone(), slot.clone(), shutdown.clone()));
+        }
+        for hostname in &cert.hostnames {
+            hosts.add(hostname, slot.clone());
+        }
+        if index == tls.default {
+            hosts.set_default(slot);
+        }
+    }
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver { hosts }));
+    // Offered in order of preference, clients without ALPN get HTTP/1.1
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+async fn load_site_cert(cert: &SiteCert) -> Result<CertifiedKey, Box<dyn Error>> {
+    match &cert.files {
+        Some(files) => {
+            let key = load_cert_files_blocking(files).await?;
+            info!("🔒 HTTPS for {} with certificate {}", cert.name, files.cert.display());
+            Ok(key)
+        }
+        None => {
+            // Issued by the local CA and reused across runs. Making keys,
+            // RSA ones above all, takes long enough to stall other requests
+            // if done on an async worker
+            let (name, names, options) = (cert.name.clone(), cert.names.clone(), cert.options);
+            let pem = tokio::task::spawn_blocking(move || {
+                CertStore::open_default()
+                    .and_then(|store| store.load_or_create(&name, &names, &options))
+                    .map_err(|e| e.to_string())
+            })
+            .await??;
+            let key = certified_key(pem.cert.as_bytes(), pem.key.as_bytes(), &cert.name, &cert.name)?;
+            info!("🔒 HTTPS for {} with a certificate from the local CA", cert.name);
+            Ok(key)
+        }
+    }
+}
+
+/// Read and check a certificate chain and its key: the files must parse, the
+/// key must belong to the first certificate and that certificate must be
+/// valid now.
+pub fn load_cert_files(files: &CertFiles) -> Result<CertifiedKey, String> {
+    let cert_name = files.cert.display().to_string();
+    let key_name = files.key.display().to_string();
+    let cert_pem = std::fs::read(&files.cert).map_err(|e| format!("Can't read certificate {}: {}", cert_name, e))?;
+    let key_pem = std::fs::read(&files.key).map_err(|e| format!("Can't read private key {}: {}", key_name, e))?;
+    certified_key(&cert_pem, &key_pem, &cert_name, &key_name)
+}
+
+/// [`load_cert_files`] on a blocking thread, so reading and parsing the files
+/// doesn't hold up an async worker.
+async fn load_cert_files_blocking(files: &CertFiles) -> Result<CertifiedKey, String> {
+    let files = files.clone();
+    tokio::task::spawn_blocking(move || load_cert_files(&files))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+fn certified_key(cert_pem: &[u8], key_pem: &[u8], cert_name: &str, key_name: &str) -> Result<CertifiedKey, String> {
+    let chain: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut &cert_pem[..])
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid certificate {}: {}", cert_name, e))?;
+    let leaf = chain.first().ok_or_else(|| format!("No certificate found in {}", cert_name))?;
+    check_validity(leaf, cert_name)?;
+
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut &key_pem[..])
+        .map_err(|e| format!("Invalid private key {}: {}", key_name, e))?
+        .ok_or_else(|| format!("No private key found in {}", key_name))?;
+
+    CertifiedKey::from_der(chain, key, &default_provider()).map_err(|e| match e {
+        rustls::Error::InconsistentKeys(_) => {
+            format!("Private key {} doesn't match certificate {}", key_name, cert_name)
+        }
+        e => format!("Unusable private key {}: {}", key_name, e),
+    })
+}
+
+fn check_validity(cert: &CertificateDer<'_>, cert_name: &str) -> Result<(), String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| format!("Invalid certificate {}: {}", cert_name, e))?;
+    let validity = cert.validity();
+    let now = x509_parser::time::ASN1Time::now();
+    if now > validity.not_after {
+        return Err(format!("Certificate {} expired on {}", cert_name, validity.not_after.to_datetime().date()));
+    }
+    if now < validity.not_before {
+        return Err(format!("Certificate {} is not valid before {}", cert_name, validity.not_before.to_datetime().date()));
+    }
+    Ok(())
+}
+
+async fn reload_on_change(files: CertFiles, slot: Arc<CertSlot>, shutdown: CancellationToken) {
+    let mut cert_changes = watch_file(files.cert.clone(), POLL_INTERVAL);
+    let mut key_changes = watch_file(files.key.clone(), POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(()) = cert_changes.recv() => {}
+            Some(()) = key_changes.recv() => {}
+            else => break,
+        }
+
+        // Certificate and key are often replaced one after the other, the
+        // change to the second one brings them back in line
+        match load_cert_files_blocking(&files).await {
+            Ok(key) => {
+                slot.replace(key);
+                info!("🔐 Reloaded certificate {}", files.cert.display());
+            }
+            Err(e) => warn!("⚠️  Keeping the previous certificate: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ssl::CertOptions, testing::TempSite};
+    use rcgen::{Certificate, CertificateParams};
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{verify_tls12_signature, verify_tls13_signature},
+        pki_types::{ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    };
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+
+    /// A certificate for `name` valid from `days_from_now.0` to `days_from_now.1`.
+    fn cert(name: &str, days_from_now: (i64, i64)) -> Certificate {
+        let mut params = CertificateParams::new(vec![name.to_string()]);
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now + time::Duration::days(days_from_now.0);
+        params.not_after = now + time::Duration::days(days_from_now.1);
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn write(site: &TempSite, name: &str, cert: &Certificate) -> CertFiles {
+        CertFiles {
+            cert: site.write(format!("{}.pem", name), cert.serialize_pem().unwrap()),
+            key: site.write(format!("{}-key.pem", name), cert.serialize_private_key_pem()),
+        }
+    }
+
+    #[test]
+    fn test_cert_files_are_checked() {
+        let site = TempSite::new();
+        let valid = write(&site, "valid", &cert("app.local", (-1, 30)));
+        assert!(load_cert_files(&valid).is_ok());
+
+        let expired = write(&site, "expired", &cert("app.local", (-30, -1)));
+        assert!(load_cert_files(&expired).unwrap_err().contains("expired on"));
+
+        let mismatched = CertFiles { cert: valid.cert.clone(), key: expired.key.clone() };
+        assert!(load_cert_files(&mismatched).unwrap_err().contains("doesn't match"));
+
+        let swapped = CertFiles { cert: valid.key.clone(), key: valid.cert.clone() };
+        assert!(load_cert_files(&swapped).unwrap_err().starts_with("No certificate found"));
+    }
+
+    #[tokio::test]
+    async fn test_changed_files_are_reloaded() {
+        let site = TempSite::new();
+        let files = write(&site, "site", &cert("old.local", (-1, 30)));
+        let slot = Arc::new(CertSlot::new(load_cert_files(&files).unwrap()));
+        let shutdown = CancellationToken::new();
+        tokio::spawn(reload_on_change(files.clone(), slot.clone(), shutdown.clone()));
+        let current = || slot.current().cert[0].clone();
+        let old = current();
+
+        // Let the watchers take their first look before the files change
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        write(&site, "site", &cert("new.local", (-1, 30)));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while current() == old {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Broken files leave the last good certificate in place
+        let new = current();
+        std::fs::write(&files.cert, "not a certificate").unwrap();
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        assert_eq!(current(), new);
+
+        shutdown.cancel();
+    }
+
+    /// The tests look at which certificate is presented, not whether it is
+    /// trusted, so the client takes any certificate with a valid signature.
+    #[derive(Debug)]
+    struct AcceptAnyCert;
+
+    impl ServerCertVerifier for AcceptAnyCert {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(message, cert, dss, &default_provider().signature_verification_algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(message, cert, dss, &default_provider().signature_verification_algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            default_provider().signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    fn site_cert(hostnames: &[&str], files: CertFiles) -> SiteCert {
+        SiteCert {
+            hostnames: hostnames.iter().map(|name| name.to_string()).collect(),
+            name: hostnames[0].to_string(),
+            names: Vec::new(),
+            options: CertOptions::default(),
+            files: Some(files),
+        }
+    }
+
+    /// Accept TLS connections on a local port until the test ends.
+    async fn serve(acceptor: TlsAcceptor) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let _stream = acceptor.accept(stream).await;
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// Complete a handshake asking for `server_name` and offering the `alpn`
+    /// protocols.
+    async fn handshake(addr: SocketAddr, server_name: &str, alpn: &[&[u8]]) -> TlsStream<TcpStream> {
+        let mut config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        TlsConnector::from(Arc::new(config)).connect(server_name, stream).await.unwrap()
+    }
+
+    /// The certificate the server presents for `server_name`.
+    async fn presented_cert(addr: SocketAddr, server_name: &str) -> CertificateDer<'static> {
+        let tls = handshake(addr, server_name, &[]).await;
+        tls.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_certificate_follows_server_name() {
+        let site = TempSite::new();
+        let app = write(&site, "app", &cert("app.local", (-1, 30)));
+        let shop = write(&site, "shop", &cert("shop.local", (-1, 30)));
+        let app_cert = load_cert_files(&app).unwrap().cert[0].clone();
+        let shop_cert = load_cert_files(&shop).unwrap().cert[0].clone();
+
+        let tls = TlsConfig {
+            certs: vec![site_cert(&["app.local", "*.app.local"], app), site_cert(&["shop.local"], shop)],
+            default: 1,
+        };
+        let shutdown = CancellationToken::new();
+        let addr = serve(create_tls_acceptor(&tls, &shutdown).await.unwrap()).await;
+
+        assert_eq!(presented_cert(addr, "app.local").await, app_cert);
+        assert_eq!(presented_cert(addr, "API.App.Local").await, app_cert);
+        assert_eq!(presented_cert(addr, "shop.local").await, shop_cert);
+        // Unknown names and connections by address get the default site's
+        assert_eq!(presented_cert(addr, "other.local").await, shop_cert);
+        assert_eq!(presented_cert(addr, "127.0.0.1").await, shop_cert);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_alpn_offers_http2() {
+        let site = TempSite::new();
+        let files = write(&site, "app", &cert("app.local", (-1, 30)));
+        let tls = TlsConfig { certs: vec![site_cert(&["app.local"], files)], default: 0 };
+        let shutdown = CancellationToken::new();
+        let addr = serve(create_tls_acceptor(&tls, &shutdown).await.unwrap()).await;
+
+        let negotiated = |alpn: &'static [&'static [u8]]| async move {
+            handshake(addr, "app.local", alpn).await.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)
+        };
+        assert_eq!(negotiated(&[b"h2", b"http/1.1"]).await.as_deref(), Some(&b"h2"[..]));
+        assert_eq!(negotiated(&[b"http/1.1"]).await.as_deref(), Some(&b"http/1.1"[..]));
+        assert_eq!(negotiated(&[]).await, None);
+
+        shutdown.cancel();
+    }
+}
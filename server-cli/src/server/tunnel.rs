@@ -0,0 +1,244 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tracing::{debug, error, info, warn};
+
+use super::{listener::ListenerShutdown, proxy::is_hop_by_hop_header, upstream::Upstream, AppState};
+use crate::events::{self, Event};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Whether the client asks to switch protocols, e.g. to a WebSocket.
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Forward an Upgrade request to the backend over its own connection. If the
+/// backend switches protocols, the client and backend connections are joined
+/// and bytes are copied both ways until either side closes or the listener
+/// shuts down. Any other backend response is passed back as is. Only `http`
+/// upstreams can be tunneled to.
+pub async fn proxy_upgrade(
+    mut req: Request,
+    state: Arc<AppState>,
+    upstream: &Upstream,
+) -> Result<Response, StatusCode> {
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let method = req.method().clone();
+    // Without a listener behind the request the tunnel only ends with its peers
+    let shutdown = req
+        .extensions()
+        .get::<ListenerShutdown>()
+        .map(|listener| listener.0.clone())
+        .unwrap_or_default();
+    let upstream_url = upstream.url_for(&path_and_query);
+    info!("🔌 Tunneling {} {} upgrade to {}", method, path_and_query, upstream_url);
+
+    let report = |error: &str| {
+        events::emit(Event::ProxyError {
+            site: &state.config.name,
+            method: method.as_str(),
+            path: &path_and_query,
+            upstream: &upstream_url,
+            error,
+        });
+    };
+
+    if upstream.is_https() {
+        let message = "Upgrade requests can't be tunneled to https upstreams";
+        warn!("❌ {}: {}", message, upstream);
+        report(message);
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+
+    let authority = upstream.authority();
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let backend_req = match backend_request(&req, &upstream.path_for(&path_and_query), &authority) {
+        Ok(backend_req) => backend_req,
+        Err(e) => {
+            warn!("❌ Invalid upgrade request: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let mut backend_resp = match send_to_backend(&authority, backend_req).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            warn!("❌ Upgrade request to backend failed: {}", e);
+            report(&e.to_string());
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    if backend_resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        let (parts, body) = backend_resp.into_parts();
+        return Ok(Response::from_parts(parts, Body::new(body)));
+    }
+
+    let backend_upgrade = hyper::upgrade::on(&mut backend_resp);
+    let site = state.config.name.clone();
+    let tunnel = async move {
+        let (client, backend) = match tokio::try_join!(client_upgrade, backend_upgrade) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                error!("Failed to upgrade {} connection: {}", site, e);
+                return;
+            }
+        };
+
+        let mut client = TokioIo::new(client);
+        let mut backend = TokioIo::new(backend);
+        match tokio::io::copy_bidirectional(&mut client, &mut backend).await {
+            Ok((sent, received)) => debug!("Tunnel closed after {} bytes up, {} bytes down", sent, received),
+            Err(e) => debug!("Tunnel closed: {}", e),
+        }
+    };
+    // Dropping the tunnel closes both connections
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tunnel => {}
+            _ = shutdown.cancelled() => debug!("Tunnel closed by listener shutdown"),
+        }
+    });
+
+    // Hand the 101 and its handshake headers (Upgrade, Sec-WebSocket-Accept, ...) to the client
+    let (parts, _) = backend_resp.into_parts();
+    Ok(Response::from_parts(parts, Body::empty()))
+}
+
+/// The client's request as sent to the backend: hop-by-hop headers are
+/// dropped except for the ones that negotiate the upgrade.
+fn backend_request(req: &Request, path_and_query: &str, authority: &str) -> Result<Request<Body>, BoxError> {
+    let mut builder = Request::builder().method(req.method()).uri(path_and_query);
+    let headers = builder.headers_mut().ok_or("invalid request")?;
+
+    for (name, value) in req.headers() {
+        if !is_hop_by_hop_header(name.as_str()) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+    if let Some(upgrade) = req.headers().get(header::UPGRADE) {
+        headers.insert(header::UPGRADE, upgrade.clone());
+    }
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::HOST, HeaderValue::from_str(authority)?);
+
+    Ok(builder.body(Body::empty())?)
+}
+
+async fn send_to_backend(authority: &str, req: Request<Body>) -> Result<hyper::Response<hyper::body::Incoming>, BoxError> {
+    let stream = TcpStream::connect(authority).await?;
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.with_upgrades().await {
+            debug!("Backend connection closed: {}", e);
+        }
+    });
+
+    Ok(sender.send_request(req).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        listener::{serve_listener, ListenerProtocol},
+        testing::spawn_backend,
+        ServerConfig,
+    };
+    use axum::Router;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::sync::CancellationToken;
+
+    /// Backend that switches to a protocol echoing every byte back.
+    async fn spawn_echo_backend() -> u16 {
+        let app = Router::new().fallback(|mut req: Request| async move {
+            if !is_upgrade_request(req.headers()) {
+                return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::empty()).unwrap();
+            }
+            tokio::spawn(async move {
+                let upgraded = hyper::upgrade::on(&mut req).await.unwrap();
+                let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+            Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::UPGRADE, "echo")
+                .header(header::CONNECTION, "upgrade")
+                .body(Body::empty())
+                .unwrap()
+        });
+
+        spawn_backend(app).await
+    }
+
+    #[test]
+    fn test_is_upgrade_request() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        assert!(!is_upgrade_request(&headers));
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        assert!(is_upgrade_request(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_is_tunneled() {
+        let backend_port = spawn_echo_backend().await;
+        let state = Arc::new(AppState::new(ServerConfig {
+            name: "test".to_string(),
+            root_dir: ".".into(),
+            proxy_port: Some(backend_port),
+            ..Default::default()
+        }));
+        let upstream = Upstream::local(backend_port);
+        let app = Router::new().fallback(move |req: Request| {
+            let state = state.clone();
+            let upstream = upstream.clone();
+            async move { proxy_upgrade(req, state, &upstream).await }
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_listener(listener, app, ListenerProtocol::default(), shutdown.clone(), Duration::from_secs(1)));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /socket HTTP/1.1\r\nHost: test\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        let head = String::from_utf8_lossy(&buf[..n]);
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.to_ascii_lowercase().contains("upgrade: echo"));
+
+        stream.write_all(b"ping").await.unwrap();
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..n], b"ping");
+
+        // Stopping the listener closes the tunnel too
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(n, 0);
+    }
+}
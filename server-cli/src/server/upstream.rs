@@ -0,0 +1,469 @@
+use reqwest::Url;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::config::{BalanceStrategy, UpstreamConfig};
+
+/// Connection errors in a row before a backend leaves the rotation
+const DEFAULT_MAX_FAILURES: u32 = 3;
+/// Seconds an ejected backend stays out of the rotation
+const DEFAULT_EJECT_SECS: u64 = 30;
+
+/// A backend that requests can be proxied to, given by its base URL. The
+/// request path is appended to the path of the base URL, so an upstream at
+/// `http://10.0.0.5:4000/v2` receives `/users` as `/v2/users`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub name: String,
+    url: Url,
+}
+
+impl Upstream {
+    pub fn parse(name: &str, url: &str) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| format!("Upstream {}: invalid URL {}: {}", name, url, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("Upstream {}: only http and https URLs are supported", name));
+        }
+        if url.host_str().is_none() {
+            return Err(format!("Upstream {}: URL has no host", name));
+        }
+        if url.query().is_some() || url.fragment().is_some() {
+            return Err(format!("Upstream {}: URL can't have a query or fragment", name));
+        }
+        Ok(Self { name: name.to_string(), url })
+    }
+
+    /// The backend on a port of this machine, as set by `proxy_to`.
+    pub fn local(port: u16) -> Self {
+        Self {
+            name: format!("localhost:{}", port),
+            url: Url::parse(&format!("http://127.0.0.1:{}", port)).expect("valid local URL"),
+        }
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.url.scheme() == "https"
+    }
+
+    /// `host:port` to connect to, with the scheme's default port filled in.
+    pub fn authority(&self) -> String {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        if host.contains(':') {
+            format!("[{}]:{}", host.trim_matches(|c| c == '[' || c == ']'), port)
+        } else {
+            format!("{}:{}", host, port)
+        }
+    }
+
+    /// Request target on the backend for a request path and query.
+    pub fn path_for(&self, path_and_query: &str) -> String {
+        let base = self.url.path().trim_end_matches('/');
+        if path_and_query.starts_with('/') {
+            format!("{}{}", base, path_and_query)
+        } else {
+            format!("{}/{}", base, path_and_query)
+        }
+    }
+
+    /// Full URL on the backend for a request path and query.
+    pub fn url_for(&self, path_and_query: &str) -> String {
+        format!("{}://{}{}", self.url.scheme(), self.authority(), self.path_for(path_and_query))
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url.as_str().trim_end_matches('/'))
+    }
+}
+
+/// A named upstream: one or more backends and how requests are spread over
+/// them. Backends leave the rotation when their health probe fails or after
+/// too many connection errors in a row, and come back once a probe passes or
+/// their ejection runs out. When no backend is left in rotation, requests go
+/// to all of them anyway so clients see the backend's own error.
+pub struct UpstreamGroup {
+    pub name: String,
+    backends: Vec<Backend>,
+    strategy: BalanceStrategy,
+    health_check: Option<HealthCheck>,
+    max_failures: u32,
+    eject_for: Duration,
+    next: AtomicUsize,
+}
+
+struct Backend {
+    upstream: Upstream,
+    /// Result of the last health probe, healthy until probed
+    healthy: AtomicBool,
+    active: AtomicUsize,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+struct HealthCheck {
+    path: String,
+    interval: Duration,
+    timeout: Duration,
+}
+
+/// Health of an upstream, as shown by `/health/upstreams`.
+#[derive(Debug, Serialize)]
+pub struct UpstreamStatus {
+    pub name: String,
+    pub strategy: BalanceStrategy,
+    pub backends: Vec<BackendStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackendStatus {
+    pub url: String,
+    pub healthy: bool,
+    pub ejected: bool,
+    pub active_connections: usize,
+    pub consecutive_failures: u32,
+}
+
+/// The backend picked for a request. It counts as an active connection
+/// until dropped.
+pub struct BackendGuard {
+    group: Arc<UpstreamGroup>,
+    index: usize,
+}
+
+impl UpstreamGroup {
+    pub fn new(name: &str, config: &UpstreamConfig) -> Result<Self, String> {
+        let urls: Vec<&String> = match (&config.url, &config.urls) {
+            (Some(url), None) => vec![url],
+            (None, Some(urls)) if !urls.is_empty() => urls.iter().collect(),
+            _ => return Err(format!("Upstream {}: needs either url or a non-empty urls list", name)),
+        };
+        let backends = urls
+            .into_iter()
+            .map(|url| Upstream::parse(name, url).map(Backend::new))
+            .collect::<Result<_, _>>()?;
+
+        let health_check = match &config.health_check {
+            Some(check) if !check.path.starts_with('/') => {
+                return Err(format!("Upstream {}: health check path must start with /", name));
+            }
+            Some(check) => Some(HealthCheck {
+                path: check.path.clone(),
+                interval: Duration::from_secs(check.interval.unwrap_or(10).max(1)),
+                timeout: Duration::from_secs(check.timeout.unwrap_or(2).max(1)),
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            backends,
+            strategy: config.strategy.unwrap_or_default(),
+            health_check,
+            max_failures: config.max_failures.unwrap_or(DEFAULT_MAX_FAILURES),
+            eject_for: Duration::from_secs(config.eject_secs.unwrap_or(DEFAULT_EJECT_SECS)),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// A group with a single backend and default settings.
+    pub fn single(upstream: Upstream) -> Self {
+        Self {
+            name: upstream.name.clone(),
+            backends: vec![Backend::new(upstream)],
+            strategy: BalanceStrategy::RoundRobin,
+            health_check: None,
+            max_failures: DEFAULT_MAX_FAILURES,
+            eject_for: Duration::from_secs(DEFAULT_EJECT_SECS),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick a backend for a request from `client`.
+    pub fn select(self: &Arc<Self>, client: Option<IpAddr>) -> BackendGuard {
+        let now = Instant::now();
+        let mut candidates: Vec<usize> = (0..self.backends.len())
+            .filter(|&index| self.backends[index].in_rotation(now))
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..self.backends.len()).collect();
+        }
+
+        let index = match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            BalanceStrategy::LeastConnections => {
+                // Rotate the starting point so ties don't always go to the first backend
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..candidates.len())
+                    .map(|offset| candidates[(start + offset) % candidates.len()])
+                    .min_by_key(|&index| self.backends[index].active.load(Ordering::Relaxed))
+                    .unwrap_or(candidates[0])
+            }
+            BalanceStrategy::IpHash => {
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                candidates[(hasher.finish() % candidates.len() as u64) as usize]
+            }
+        };
+
+        self.backends[index].active.fetch_add(1, Ordering::Relaxed);
+        BackendGuard { group: self.clone(), index }
+    }
+
+    pub fn status(&self) -> UpstreamStatus {
+        let now = Instant::now();
+        UpstreamStatus {
+            name: self.name.clone(),
+            strategy: self.strategy,
+            backends: self
+                .backends
+                .iter()
+                .map(|backend| BackendStatus {
+                    url: backend.upstream.to_string(),
+                    healthy: backend.healthy.load(Ordering::Relaxed),
+                    ejected: backend.is_ejected(now),
+                    active_connections: backend.active.load(Ordering::Relaxed),
+                    consecutive_failures: backend.failures.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether at least one backend is in rotation.
+    pub fn is_available(&self) -> bool {
+        let now = Instant::now();
+        self.backends.iter().any(|backend| backend.in_rotation(now))
+    }
+
+    /// Probe the backends in the background while the group is in use.
+    pub fn start_health_checks(self: &Arc<Self>, client: reqwest::Client) {
+        let Some(interval) = self.health_check.as_ref().map(|check| check.interval) else {
+            return;
+        };
+
+        let group = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // The site's router was rebuilt or dropped
+                let Some(group) = group.upgrade() else { break };
+                group.probe(&client).await;
+            }
+        });
+    }
+
+    async fn probe(&self, client: &reqwest::Client) {
+        let Some(check) = &self.health_check else { return };
+
+        let probes = self.backends.iter().map(|backend| async move {
+            let healthy = client
+                .get(backend.upstream.url_for(&check.path))
+                .timeout(check.timeout)
+                .send()
+                .await
+                .is_ok_and(|resp| resp.status().is_success() || resp.status().is_redirection());
+
+            let was_healthy = backend.healthy.swap(healthy, Ordering::Relaxed);
+            if healthy {
+                // A passing probe also ends an ejection early
+                *backend.ejected_until.lock().unwrap() = None;
+                backend.failures.store(0, Ordering::Relaxed);
+            }
+            match (was_healthy, healthy) {
+                (false, true) => info!("💚 {} backend {} is healthy again", self.name, backend.upstream),
+                (true, false) => warn!("💔 {} backend {} failed its health check", self.name, backend.upstream),
+                _ => {}
+            }
+        });
+        futures::future::join_all(probes).await;
+    }
+}
+
+impl Backend {
+    fn new(upstream: Upstream) -> Self {
+        Self {
+            upstream,
+            healthy: AtomicBool::new(true),
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.lock().unwrap().is_some_and(|until| now < until)
+    }
+
+    fn in_rotation(&self, now: Instant) -> bool {
+        self.healthy.load(Ordering::Relaxed) && !self.is_ejected(now)
+    }
+}
+
+impl BackendGuard {
+    pub fn upstream(&self) -> &Upstream {
+        &self.backend().upstream
+    }
+
+    /// Record whether the backend could be connected to. Enough failures in
+    /// a row take it out of rotation.
+    pub fn report(&self, reachable: bool) {
+        let backend = self.backend();
+        if reachable {
+            backend.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = backend.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let max_failures = self.group.max_failures;
+        if max_failures > 0 && failures >= max_failures {
+            backend.failures.store(0, Ordering::Relaxed);
+            *backend.ejected_until.lock().unwrap() = Some(Instant::now() + self.group.eject_for);
+            warn!(
+                "⛔ {} backend {} failed {} times in a row, out of rotation for {}s",
+                self.group.name,
+                backend.upstream,
+                failures,
+                self.group.eject_for.as_secs()
+            );
+        }
+    }
+
+    fn backend(&self) -> &Backend {
+        &self.group.backends[self.index]
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend().active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Check the upstreams declared by a site.
+pub fn compile_upstreams(
+    upstreams: &BTreeMap<String, UpstreamConfig>,
+) -> Result<BTreeMap<String, Arc<UpstreamGroup>>, String> {
+    upstreams
+        .iter()
+        .map(|(name, config)| {
+            if name.is_empty() {
+                return Err("Upstream names can't be empty".to_string());
+            }
+            Ok((name.clone(), Arc::new(UpstreamGroup::new(name, config)?)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::spawn_backend;
+
+    #[test]
+    fn test_upstream_paths() {
+        let local = Upstream::local(3000);
+        assert_eq!(local.url_for("/api/users?page=2"), "http://127.0.0.1:3000/api/users?page=2");
+        assert_eq!(local.authority(), "127.0.0.1:3000");
+
+        let remote = Upstream::parse("auth", "https://10.0.0.5/v2/").unwrap();
+        assert!(remote.is_https());
+        assert_eq!(remote.authority(), "10.0.0.5:443");
+        assert_eq!(remote.path_for("/login"), "/v2/login");
+        assert_eq!(remote.to_string(), "https://10.0.0.5/v2");
+
+        let ipv6 = Upstream::parse("v6", "http://[::1]:5000").unwrap();
+        assert_eq!(ipv6.authority(), "[::1]:5000");
+    }
+
+    fn group(urls: &[&str], strategy: BalanceStrategy) -> Arc<UpstreamGroup> {
+        let config = UpstreamConfig {
+            urls: Some(urls.iter().map(|url| url.to_string()).collect()),
+            strategy: Some(strategy),
+            max_failures: Some(2),
+            ..Default::default()
+        };
+        Arc::new(UpstreamGroup::new("api", &config).unwrap())
+    }
+
+    #[test]
+    fn test_round_robin_skips_ejected_backends() {
+        let group = group(&["http://a:1", "http://b:1", "http://c:1"], BalanceStrategy::RoundRobin);
+        let picks: Vec<String> = (0..3).map(|_| group.select(None).upstream().to_string()).collect();
+        assert_eq!(picks, ["http://a:1", "http://b:1", "http://c:1"]);
+
+        // Two connection errors in a row eject b
+        for _ in 0..2 {
+            for guard in (0..3).map(|_| group.select(None)) {
+                guard.report(guard.upstream().to_string() != "http://b:1");
+            }
+        }
+        assert!(group.status().backends[1].ejected);
+        assert!((0..6).all(|_| group.select(None).upstream().to_string() != "http://b:1"));
+    }
+
+    #[test]
+    fn test_least_connections_and_ip_hash() {
+        let group = group(&["http://a:1", "http://b:1"], BalanceStrategy::LeastConnections);
+        let busy = group.select(None);
+        let other = group.select(None);
+        assert_ne!(busy.upstream(), other.upstream());
+        drop(other);
+        assert_ne!(group.select(None).upstream(), busy.upstream());
+        assert_eq!(group.status().backends.iter().map(|b| b.active_connections).sum::<usize>(), 1);
+
+        let group = self::group(&["http://a:1", "http://b:1", "http://c:1"], BalanceStrategy::IpHash);
+        let client = Some("192.168.1.7".parse().unwrap());
+        let first = group.select(client).upstream().clone();
+        assert!((0..5).all(|_| group.select(client).upstream() == &first));
+    }
+
+    #[test]
+    fn test_no_backend_in_rotation_uses_all() {
+        let group = group(&["http://a:1"], BalanceStrategy::RoundRobin);
+        group.select(None).report(false);
+        group.select(None).report(false);
+        assert!(!group.is_available());
+        assert_eq!(group.select(None).upstream().to_string(), "http://a:1");
+    }
+
+    #[tokio::test]
+    async fn test_health_check_takes_backend_out_of_rotation() {
+        let app = axum::Router::new().route("/ping", axum::routing::get(|| async { "pong" }));
+        let healthy = format!("http://127.0.0.1:{}", spawn_backend(app).await);
+
+        let config = UpstreamConfig {
+            urls: Some(vec![healthy.clone(), "http://127.0.0.1:1".to_string()]),
+            health_check: Some(crate::config::HealthCheckConfig { path: "/ping".to_string(), interval: None, timeout: None }),
+            ..Default::default()
+        };
+        let group = UpstreamGroup::new("api", &config).unwrap();
+        group.probe(&reqwest::Client::new()).await;
+
+        let status = group.status();
+        assert!(status.backends[0].healthy);
+        assert!(!status.backends[1].healthy);
+    }
+
+    #[test]
+    fn test_invalid_upstreams() {
+        assert!(Upstream::parse("a", "localhost:3000").is_err());
+        assert!(Upstream::parse("a", "ftp://files.local").is_err());
+        assert!(Upstream::parse("a", "http://api.local/?debug=1").is_err());
+        assert!(UpstreamGroup::new("a", &UpstreamConfig::default()).is_err());
+        assert!(UpstreamGroup::new("a", &UpstreamConfig { urls: Some(Vec::new()), ..Default::default() }).is_err());
+    }
+}
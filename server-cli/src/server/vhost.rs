@@ -0,0 +1,178 @@
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use std::{collections::HashMap, sync::Arc};
+use tower::util::ServiceExt;
+use tracing::debug;
+
+/// Routes requests arriving on one shared listener to the site whose
+/// hostname matches the `Host` header. Hostnames are matched
+/// case-insensitively with the port stripped; a leading `*.` matches any
+/// subdomain. Requests for unknown hosts go to the default site, or get a
+/// `421 Misdirected Request` when no default is set.
+///
+/// The same table picks the certificate for a TLS server name (see `tls`).
+#[derive(Clone)]
+pub struct VirtualHosts<T = Router> {
+    exact: HashMap<String, T>,
+    wildcard: Vec<(String, T)>,
+    default: Option<T>,
+}
+
+impl<T> Default for VirtualHosts<T> {
+    fn default() -> Self {
+        Self { exact: HashMap::new(), wildcard: Vec::new(), default: None }
+    }
+}
+
+impl<T> VirtualHosts<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, hostname: &str, target: T) {
+        let hostname = hostname.trim().to_ascii_lowercase();
+        match hostname.strip_prefix("*.") {
+            Some(suffix) => self.wildcard.push((format!(".{}", suffix), target)),
+            None => {
+                self.exact.insert(hostname, target);
+            }
+        }
+    }
+
+    pub fn set_default(&mut self, target: T) {
+        self.default = Some(target);
+    }
+
+    /// The target for a lowercase host name, or the default.
+    pub fn resolve(&self, host: Option<&str>) -> Option<&T> {
+        let matched = host.and_then(|host| {
+            self.exact.get(host).or_else(|| {
+                self.wildcard
+                    .iter()
+                    .find(|(suffix, _)| host.ends_with(suffix.as_str()))
+                    .map(|(_, target)| target)
+            })
+        });
+        matched.or(self.default.as_ref())
+    }
+}
+
+impl VirtualHosts<Router> {
+    /// Wrap the host table in a router that dispatches every request.
+    pub fn into_router(self) -> Router {
+        let hosts = Arc::new(self);
+        Router::new().fallback(move |req: Request| {
+            let hosts = hosts.clone();
+            async move {
+                let host = request_host(&req);
+                match hosts.resolve(host.as_deref()) {
+                    Some(router) => match router.clone().oneshot(req).await {
+                        Ok(response) => response,
+                        Err(never) => match never {},
+                    },
+                    None => misdirected(host.as_deref()),
+                }
+            }
+        })
+    }
+}
+
+/// Extract the requested hostname (lowercase, without port) from the request
+/// URI authority or the `Host` header.
+pub fn request_host(req: &Request) -> Option<String> {
+    let raw = req
+        .uri()
+        .host()
+        .map(str::to_string)
+        .or_else(|| {
+            req.headers()
+                .get(header::HOST)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        })?;
+    normalize_host(&raw)
+}
+
+fn normalize_host(raw: &str) -> Option<String> {
+    let raw = raw.trim();
+    let host = if let Some(rest) = raw.strip_prefix('[') {
+        // IPv6 literal, e.g. [::1]:8080
+        rest.split(']').next().unwrap_or(rest)
+    } else {
+        raw.split(':').next().unwrap_or(raw)
+    };
+    let host = host.trim_end_matches('.');
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_ascii_lowercase())
+    }
+}
+
+fn misdirected(host: Option<&str>) -> Response {
+    let host = host.unwrap_or("<none>");
+    debug!("No site configured for host {}", host);
+    (
+        StatusCode::MISDIRECTED_REQUEST,
+        format!("No site is configured for host '{}' on this port\n", host),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get};
+
+    fn site(name: &'static str) -> Router {
+        Router::new().route("/", get(move || async move { name }))
+    }
+
+    async fn get_with_host(router: &Router, host: &str) -> (StatusCode, String) {
+        let req = Request::builder()
+            .uri("/")
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(req).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Blog.Local:8080").as_deref(), Some("blog.local"));
+        assert_eq!(normalize_host("shop.local.").as_deref(), Some("shop.local"));
+        assert_eq!(normalize_host("[::1]:80").as_deref(), Some("::1"));
+        assert_eq!(normalize_host(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_by_host_header() {
+        let mut hosts = VirtualHosts::new();
+        hosts.add("blog.local", site("blog"));
+        hosts.add("*.shop.local", site("shop"));
+        let router = hosts.into_router();
+
+        assert_eq!(get_with_host(&router, "BLOG.local:80").await.1, "blog");
+        assert_eq!(get_with_host(&router, "eu.shop.local").await.1, "shop");
+
+        let (status, _) = get_with_host(&router, "unknown.local").await;
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_host_uses_default() {
+        let mut hosts = VirtualHosts::new();
+        hosts.add("blog.local", site("blog"));
+        hosts.set_default(site("fallback"));
+        let router = hosts.into_router();
+
+        assert_eq!(get_with_host(&router, "192.168.1.10").await.1, "fallback");
+    }
+}
@@ -0,0 +1,43 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// How often watched files are checked for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watch a file by polling its modification time and size. A message is sent
+/// every time the file changes, appears or disappears. Polling keeps this
+/// working the same way on every platform and across editors that save by
+/// replacing the file.
+pub fn watch_file(path: PathBuf, interval: Duration) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let mut last = fingerprint(&path).await;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            let current = fingerprint(&path).await;
+            if current == last {
+                continue;
+            }
+            debug!("Detected change in {}", path.display());
+            last = current;
+            if tx.send(()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+async fn fingerprint(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}